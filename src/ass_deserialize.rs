@@ -1,12 +1,93 @@
 use std::vec;
-use crate::error::Error;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use serde::{Serialize, Serializer};
+use crate::error::{Error, Location, Warning};
+use crate::font_match::NOT_FOUND;
+use crate::override_tags::{tokenize, OverrideTag, Segment};
+use crate::sfnt::VerticalSupport;
 
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
 pub struct AssFont {
   pub facename: String,
   pub bold: bool,
   pub italic: bool,
-  pub path: String
+  /// Charset from the style's Encoding field or `\fe`, e.g. 128 for Shift-JIS.
+  pub encoding: u8,
+  /// The facename starts with `@`, so libass lays the text out top to bottom.
+  pub vertical: bool,
+  /// The matched font file, None until `find_font_files` found one
+  #[serde(serialize_with = "serialize_path")]
+  pub path: Option<PathBuf>,
+  pub quality: MatchQuality,
+  /// What the matched file offers for vertical text, only looked up for vertical fonts
+  pub vertical_support: Option<VerticalSupport>,
+  /// Name of the matched file inside the container, once the attachments are planned
+  pub attachment: Option<String>,
+  /// Why the mapping file entry for this font can't be used, e.g. because its file doesn't exist
+  pub map_error: Option<String>
+}
+
+impl AssFont {
+  /// A font as a script asks for it, before `find_font_files` looked for a file.
+  pub fn new(facename: String, bold: bool, italic: bool, encoding: u8) -> AssFont {
+    AssFont {
+      vertical: facename.starts_with('@'),
+      facename,
+      bold,
+      italic,
+      encoding,
+      path: None,
+      quality: MatchQuality::Unresolved,
+      vertical_support: None,
+      attachment: None,
+      map_error: None
+    }
+  }
+
+  /// The matched file for printing, or `NOT_FOUND`.
+  pub fn path_display(&self) -> String {
+    match &self.path {
+      Some(path) => path.display().to_string(),
+      None => NOT_FOUND.to_string()
+    }
+  }
+
+  /// Explains why a vertical font won't look right, if it doesn't support vertical text.
+  pub fn vertical_warning(&self) -> Option<String> {
+    let missing = self.vertical_support?.missing()?;
+    Some(format!("\"{}\" is used vertically, but {} has no {}", self.facename, self.path_display(), missing))
+  }
+}
+
+/// Font paths come from the file system and don't have to be valid UTF-8, so they can't be
+/// serialized as they are.
+fn serialize_path<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+  path.as_ref().map(|path| path.to_string_lossy()).serialize(serializer)
+}
+
+/// Which name of the matched font equals the facename, best first.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchQuality {
+  /// Picked in a mapping file
+  Mapped,
+  FullName,
+  Family,
+  PostScriptName,
+  Unresolved
+}
+
+impl Display for MatchQuality {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      MatchQuality::Mapped => formatter.write_str("mapping file"),
+      MatchQuality::FullName => formatter.write_str("full name"),
+      MatchQuality::Family => formatter.write_str("family"),
+      MatchQuality::PostScriptName => formatter.write_str("PostScript name"),
+      MatchQuality::Unresolved => formatter.write_str("unresolved")
+    }
+  }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -15,9 +96,50 @@ pub struct AssStyle {
   pub font: AssFont
 }
 
+/// An event whose style doesn't exist, and the style that got used in its place.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+pub struct StyleReference {
+  pub style: String,
+  pub line: usize,
+  pub fallback: String
+}
+
 #[derive(Debug, Clone)]
 pub struct AssFile {
-  pub fonts: Vec<AssFont>
+  pub fonts: Vec<AssFont>,
+  /// Name of the encoding the file was stored in before it got converted to UTF-8
+  pub text_encoding: String,
+  /// Recoverable problems found while parsing
+  pub warnings: Vec<Warning>,
+  pub undefined_styles: Vec<StyleReference>,
+  /// PlayResX/Y, with libass' defaults for missing values
+  pub play_res: (u32, u32),
+  /// End of the last event in centiseconds
+  pub end: u64
+}
+
+/// A line of the script, remembering where it came from for diagnostics.
+#[derive(Debug, Clone)]
+pub struct ScriptLine {
+  /// 1-based
+  pub number: usize,
+  /// Section name without the brackets, e.g. "V4+ Styles"
+  pub section: String,
+  pub text: String
+}
+
+impl ScriptLine {
+  /// Points at `length` characters starting at the 0-based character `start`.
+  pub fn location(&self, start: usize, length: usize) -> Location {
+    Location {
+      file: None,
+      line: self.number,
+      column: start + 1,
+      length,
+      section: Some(self.section.clone()),
+      text: self.text.clone()
+    }
+  }
 }
 
 impl AssFile {
@@ -25,37 +147,37 @@ impl AssFile {
     let styles = Self::get_styles(&f)?;
     let events = Self::get_event_lines(&f)?;
     
-    let font_trim = Self::trim_to_fonts(styles, events);
-    let fonts = font_trim.expect("Failed to trim to font_names");
-    Ok(AssFile {fonts})
+    Self::trim_to_fonts(styles, events, play_res(&f))
   }
 
-  fn get_styles(f: &str) -> Result<Vec<String>, Error> {
+  fn get_styles(f: &str) -> Result<Vec<ScriptLine>, Error> {
     let mut header: Option<String> = None;
-    let mut lines: Vec<String> = vec![];
-    for line in f.lines() {
+    let mut lines: Vec<ScriptLine> = vec![];
+    for (index, line) in f.lines().enumerate() {
       if line.starts_with('[') && line.ends_with(']') && line.contains("Styles") {
-        header = Some(line.to_string());
+        header = Some(line[1..line.len() - 1].to_string());
         continue
       }
       if line.starts_with('[') && line.ends_with(']') && line.contains("Events") {
         return Ok(lines)
       }
-      if header.is_some() && ! line.is_empty() && ! line.starts_with("Format:") {
-        lines.append(&mut vec![line.to_string()]);
+      if let Some(section) = &header {
+        if ! line.is_empty() && ! line.starts_with("Format:") {
+          lines.push(ScriptLine { number: index + 1, section: section.clone(), text: line.to_string() });
+        }
       }
     }
     Err(Error::MissingStylesInfo)
   }
 
-  fn get_event_lines(f: &str) -> Result<Vec<String>, Error> {
+  fn get_event_lines(f: &str) -> Result<Vec<ScriptLine>, Error> {
     let mut header: Option<String> = None;
-    let mut lines: Vec<String> = vec![];
-    for line in f.lines() {
+    let mut lines: Vec<ScriptLine> = vec![];
+    for (index, line) in f.lines().enumerate() {
       if line.starts_with('[') && line.ends_with(']') {
         header = Some(line.to_string());
       } else if header == Some("[Events]".to_string()) && ! line.is_empty() && ! line.starts_with("Format:") && ! line.starts_with("Comment:") {
-        lines.append(&mut vec![line.to_string()]);
+        lines.push(ScriptLine { number: index + 1, section: "Events".to_string(), text: line.to_string() });
         continue;
       }
     }
@@ -66,16 +188,31 @@ impl AssFile {
     }
   }
 
-  fn trim_to_fonts(style_lines: Vec<String>, events: Vec<String>) -> Result<Vec<AssFont>, Error> {
+  fn trim_to_fonts(style_lines: Vec<ScriptLine>, events: Vec<ScriptLine>, play_res: (u32, u32)) -> Result<AssFile, Error> {
     let mut fonts: Vec<AssFont> = vec![];
     let mut styles: Vec<AssStyle> = vec![];
+    let mut warnings: Vec<Warning> = vec![];
+    let mut undefined_styles: Vec<StyleReference> = vec![];
+    let mut end: u64 = 0;
     
-    for line in style_lines {
+    for script_line in style_lines {
+      let line = &script_line.text;
+      // Name, Fontname, ..., Bold, Italic
+      let fields = line.split(',').count();
+      if fields < 9 {
+        warnings.push(Warning {
+          message: format!("style has {} fields, expected at least 9, and was skipped", fields),
+          location: script_line.location(0, line.chars().count())
+        });
+        continue;
+      }
       let mut font = String::new();
       let mut bold: bool = false;
       let mut italic: bool = false;
       let mut comma_ed: u8 = 0;
       let mut style_name: String = String::new();
+      // Encoding is the last field of both V4 and V4+ styles
+      let encoding: u8 = line.rsplit(',').next().and_then(|e| e.trim().parse().ok()).unwrap_or(1);
 
       for ch in line.trim_start_matches("Style: ").chars() {
         if ch == ',' {
@@ -85,26 +222,13 @@ impl AssFile {
         } else if comma_ed == 1 {
           font.push_str(ch.to_string().as_str());
         } else if comma_ed == 7 {
-          if ch == '0' {
-            bold = false;
-          } else {
-            bold = true;
-          }
+          bold = ch != '0';
         } else if comma_ed == 8 {
-          if ch == '0' {
-            italic = false;
-          } else {
-            italic = true;
-          }
+          italic = ch != '0';
         }
       }
 
-      let assfont: AssFont = AssFont {
-        facename: font,
-        bold,
-        italic,
-        path: "".to_string()
-      };
+      let assfont = AssFont::new(font, bold, italic, encoding);
 
       styles.append(&mut vec![AssStyle {
         name: style_name,
@@ -112,120 +236,205 @@ impl AssFile {
       }]);
     }
     
-    for line in events {
-      let mut style_name: String = String::new();
-      let mut facename: String = String::new();
-      let mut comma_ed: u8 = 0;
-      let mut bold: bool = false;
-      let mut italic: bool = false;
-      for ch in line.chars() {
-        if ch == ',' {
-          comma_ed += 1;
-          
-          if comma_ed == 4 {
-            let style = if let Some(style) = styles.iter().find(| s | s.name == style_name) {
-              style
-            } else {
-              println!("fdasfs");
-              styles.get(0).unwrap()
-            };
-            facename = style.font.facename.clone();
-            bold = style.font.bold;
-            italic = style.font.italic;
-            break;
-          }
-        } else if comma_ed == 3 {
-          style_name.push(ch);
-        }
-      }
-
-      if facename == "" {
-        println!("{}", line);
+    let default_style = default_style(&styles);
+    for script_line in events {
+      let line = script_line.text.clone();
+      let fields: Vec<&str> = line.splitn(10, ',').collect();
+      if fields.len() < 10 {
+        warnings.push(Warning {
+          message: "event has too few fields and was skipped".to_string(),
+          location: script_line.location(0, line.chars().count())
+        });
+        continue;
       }
+      end = end.max(parse_time(fields[2]).unwrap_or(0));
 
-      if line.contains(r#"\fn"#) || line.contains(r#"\i"#) || line.contains(r#"\b"#) {
-        let tags = get_tags(line.clone());
-        if let Some(tagged) = tags {
-          for tag in tagged {
-            if tag == "\\b1" {
-              bold = true;
-            } else if tag == "\\b0" {
-              bold = false;
-            } else if tag == r#"\i1"# {
-              italic = true;
-            } else if tag == r#"\i0"# {
-              italic = false;
-            } else if tag.starts_with("\\fn") {
-              facename = tag.trim_start_matches("\\fn").to_string();
-            }
-          }
-
-          let assfont = AssFont {
-            facename: facename.to_string(),
-            bold,
-            italic,
-            path: "".to_string()
-          };
+      let style_name = fields[3];
+      let style = if let Some(style) = lookup_style(&styles, style_name) {
+        style
+      } else {
+        let style_start = fields[..3].iter().map(|f| f.chars().count() + 1).sum();
+        warnings.push(Warning {
+          message: format!("event uses undefined style \"{}\", falling back to \"{}\" ({})", style_name, default_style.name, default_style.font.facename),
+          location: script_line.location(style_start, style_name.chars().count())
+        });
+        undefined_styles.push(StyleReference {
+          style: style_name.to_string(),
+          line: script_line.number,
+          fallback: default_style.name.clone()
+        });
+        &default_style
+      };
 
-          if ! fonts.contains(&assfont) {
-            fonts.append(&mut vec![assfont]);
+      // Every run of text is rendered with the font the tags before it selected
+      let mut state = TagState { font: style.font.clone(), base: style, drawing: false };
+      for segment in tokenize(fields[9]).segments {
+        if let Segment::Tags(tags) = &segment {
+          for tag in tags {
+            state.apply(tag, style, &styles);
           }
-        } else {
-          continue
-        }
-      } else {
-        let assfont = AssFont {
-          facename: facename.to_string(),
-          bold,
-          italic,
-          path: "".to_string()
-        };
-
-        if ! fonts.contains(&assfont) {
-          fonts.append(&mut vec![assfont]);
+        } else if segment.is_visible_text() && ! state.drawing && ! fonts.contains(&state.font) {
+          fonts.push(state.font.clone());
         }
       }
     };
 
     if ! fonts.is_empty() {
-      Ok(fonts)
+      Ok(AssFile {fonts, text_encoding: "UTF-8".to_string(), warnings, undefined_styles, play_res, end})
     } else {
       Err(Error::FailedParsingFonts)
     }
   }
 }
 
-fn get_tags(line: String) -> Option<Vec<String>> {
-  let mut styles: Vec<String> = vec![];
-  let mut record: bool = false;
-  let mut temp: String = String::new();
+/// The font an event is rendered with at some point of its text.
+struct TagState<'a> {
+  font: AssFont,
+  /// Style that empty tags like `\b` fall back to, changed by `\r`
+  base: &'a AssStyle,
+  /// Inside `\p1` drawings no glyphs are rendered
+  drawing: bool
+}
 
-  for character in line.chars() {
-    if character == '{' {
-      record = true;
-      continue;
-    } else if character == '}' {
-      record = false;
-      styles.append(&mut vec![temp.clone()]);
-      temp.clear();
-      continue;
+impl<'a> TagState<'a> {
+  /// Applies a tag the way libass does, including tags inside `\t`, which it doesn't animate.
+  fn apply(&mut self, tag: &OverrideTag, line_style: &'a AssStyle, styles: &'a [AssStyle]) {
+    let argument = tag.argument.trim();
+    match tag.name {
+      "b" => self.font.bold = match argument.parse::<u32>() {
+        Ok(0) => false,
+        Ok(1) => true,
+        // libass treats 2 to 99 as invalid, like a missing value
+        Ok(weight) if weight >= 100 => weight >= 700,
+        _ => self.base.font.bold
+      },
+      "i" => self.font.italic = match argument.parse::<u32>() {
+        Ok(0) => false,
+        Ok(1) => true,
+        _ => self.base.font.italic
+      },
+      "fn" => {
+        self.font.facename = if argument.is_empty() { self.base.font.facename.clone() } else { argument.to_string() };
+        self.font.vertical = self.font.facename.starts_with('@');
+      },
+      "fe" => self.font.encoding = argument.parse().unwrap_or(self.base.font.encoding),
+      "r" => {
+        self.base = lookup_style(styles, argument).filter(|_| ! argument.is_empty()).unwrap_or(line_style);
+        self.font = self.base.font.clone();
+      },
+      "p" => self.drawing = argument.parse::<i32>().is_ok_and(|scale| scale > 0),
+      _ => {}
     }
-    if record {
-      temp.push_str(character.to_string().as_str())
+  }
+}
+
+/// Every line that belongs to a section, without blank lines and `;` comments.
+pub fn script_lines(f: &str) -> Vec<ScriptLine> {
+  let mut section: Option<String> = None;
+  let mut lines: Vec<ScriptLine> = vec![];
+  for (index, line) in f.lines().enumerate() {
+    let trimmed = line.trim();
+    if trimmed.starts_with('[') && trimmed.ends_with(']') {
+      section = Some(trimmed[1..trimmed.len() - 1].to_string());
+    } else if let Some(section) = &section {
+      if ! trimmed.is_empty() && ! trimmed.starts_with(';') {
+        lines.push(ScriptLine { number: index + 1, section: section.clone(), text: line.to_string() });
+      }
     }
-  };
+  }
+  lines
+}
 
-  if styles.is_empty() {
-    return None;
+/// PlayResX and PlayResY. Like libass, a missing one is derived from the other as if the script was
+/// 4:3, and 384x288 is used if both are missing.
+fn play_res(f: &str) -> (u32, u32) {
+  let lines = script_lines(f);
+  let info = |key: &str| lines.iter().rev()
+    .filter(|l| l.section == "Script Info")
+    .find_map(|l| l.text.split_once(':').filter(|(k, _)| k.trim() == key).map(|(_, v)| v.trim().parse::<u32>().ok()))
+    .flatten()
+    .filter(|value| *value > 0);
+  match (info("PlayResX"), info("PlayResY")) {
+    (Some(x), Some(y)) => (x, y),
+    (Some(x), None) => (x, if x == 1280 { 1024 } else { x * 3 / 4 }),
+    (None, Some(y)) => (if y == 1024 { 1280 } else { y * 4 / 3 }, y),
+    (None, None) => (384, 288)
   }
+}
 
-  let mut tags: Vec<String> = vec![];
-  for style in styles {
-    let splits = style.split_terminator('\\');
-    for str in splits {
-      tags.append(&mut vec![("\\".to_owned() + str).to_string()]);
-    }
-  };
-  
-  Some(tags)
+/// Parses an event time like `0:01:02.50` into centiseconds.
+fn parse_time(time: &str) -> Option<u64> {
+  let (hours, rest) = time.trim().split_once(':')?;
+  let (minutes, seconds) = rest.split_once(':')?;
+  let (seconds, centiseconds) = seconds.split_once('.').unwrap_or((seconds, "0"));
+  let [hours, minutes, seconds, centiseconds] = [hours, minutes, seconds, centiseconds].map(|v| v.parse::<u64>().ok());
+  Some(((hours? * 60 + minutes?) * 60 + seconds?) * 100 + centiseconds?)
+}
+
+/// Whether an event's style field refers to the style called `name`, following `lookup_style`.
+pub fn refers_to(reference: &str, name: &str) -> bool {
+  let reference = reference.trim_start_matches('*');
+  reference == name || (reference.eq_ignore_ascii_case("Default") && name.eq_ignore_ascii_case("Default"))
+}
+
+/// Finds the style an event refers to the way libass does: leading asterisks are ignored, "Default"
+/// is matched case-insensitively and later definitions win over earlier ones.
+fn lookup_style<'a>(styles: &'a [AssStyle], name: &str) -> Option<&'a AssStyle> {
+  let name = name.trim_start_matches('*');
+  if name.eq_ignore_ascii_case("Default") {
+    return styles.iter().rev().find(|s| s.name.eq_ignore_ascii_case("Default"));
+  }
+  styles.iter().rev().find(|s| s.name == name)
+}
+
+/// What libass falls back to for undefined styles: the last style called "Default", or its built-in
+/// Arial style if the script doesn't define one.
+fn default_style(styles: &[AssStyle]) -> AssStyle {
+  if let Some(style) = styles.iter().rev().find(|s| s.name.eq_ignore_ascii_case("Default")) {
+    return style.clone();
+  }
+  AssStyle {
+    name: "Default".to_string(),
+    font: AssFont::new("Arial".to_string(), false, false, 1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FORMAT: &str = "Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic";
+
+  fn script(styles: &str, events: &str) -> String {
+    format!(
+      "[Script Info]\nScriptType: v4.00+\n\n[V4+ Styles]\n{}\n{}\n\n[Events]\n\
+      Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n{}\n",
+      FORMAT, styles, events
+    )
+  }
+
+  #[test]
+  fn short_styles_are_skipped_with_a_warning() {
+    let text = script(
+      "Style: Broken,Foo,20\nStyle: Default,Bar,20,&H0,&H0,&H0,&H0,-1,0",
+      "Dialogue: 0,0:00:00.00,0:00:01.00,Default,,0,0,0,,Hello"
+    );
+    let file = AssFile::get_fonts(text).unwrap();
+    assert_eq!(file.fonts.iter().map(|f| (f.facename.as_str(), f.bold)).collect::<Vec<_>>(), vec![("Bar", true)]);
+    assert_eq!(file.warnings.len(), 1);
+    assert_eq!(file.warnings[0].message, "style has 3 fields, expected at least 9, and was skipped");
+    assert_eq!((file.warnings[0].location.line, file.warnings[0].location.text.as_str()), (6, "Style: Broken,Foo,20"));
+  }
+
+  #[test]
+  fn short_events_are_skipped_with_a_warning() {
+    let text = script(
+      "Style: Default,Bar,20,&H0,&H0,&H0,&H0,0,0",
+      "Dialogue: 0,0:00:00.00\nDialogue: 0,0:00:00.00,0:00:01.00,Default,,0,0,0,,Hello"
+    );
+    let file = AssFile::get_fonts(text).unwrap();
+    assert_eq!(file.fonts.len(), 1);
+    assert_eq!(file.warnings[0].message, "event has too few fields and was skipped");
+    assert_eq!(file.warnings[0].location.line, 10);
+  }
 }
@@ -0,0 +1,96 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+/// Turns the raw bytes of a subtitle file into text and tells which encoding it was in.
+///
+/// A byte order mark always wins. Without one, UTF-16 is recognized by its zero bytes and UTF-8 by
+/// being valid. Only files that are neither get decoded with the `legacy` code page from `--encoding`,
+/// so a batch can mix old Shift-JIS scripts with current ones.
+pub fn decode(bytes: &[u8], legacy: Option<&'static Encoding>) -> Result<(String, &'static Encoding), String> {
+  if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+    return decode_with(&bytes[bom_length..], encoding);
+  }
+  if let Some(encoding) = sniff_utf16(bytes) {
+    return decode_with(bytes, encoding);
+  }
+  match (std::str::from_utf8(bytes), legacy) {
+    (Ok(text), _) => Ok((text.to_string(), UTF_8)),
+    (Err(_), Some(encoding)) => decode_with(bytes, encoding),
+    (Err(err), None) => Err(format!("Not valid UTF-8 ({}), pass --encoding for legacy code pages like shift_jis or gbk", err))
+  }
+}
+
+fn decode_with(bytes: &[u8], encoding: &'static Encoding) -> Result<(String, &'static Encoding), String> {
+  let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+  if had_errors {
+    return Err(format!("Not valid {}", encoding.name()));
+  }
+  Ok((text.into_owned(), encoding))
+}
+
+/// ASS files are mostly ASCII, so UTF-16 without a BOM shows up as every other byte being zero.
+fn sniff_utf16(bytes: &[u8]) -> Option<&'static Encoding> {
+  let sample = &bytes[..bytes.len().min(4096) & !1];
+  if sample.is_empty() {
+    return None;
+  }
+  let pairs = sample.len() / 2;
+  let even_zeros = sample.iter().step_by(2).filter(|b| **b == 0).count();
+  let odd_zeros = sample.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+  if odd_zeros * 2 > pairs && even_zeros * 8 < pairs {
+    Some(UTF_16LE)
+  } else if even_zeros * 2 > pairs && odd_zeros * 8 < pairs {
+    Some(UTF_16BE)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use encoding_rs::SHIFT_JIS;
+
+  use super::*;
+
+  fn utf16le(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect()
+  }
+
+  fn utf16be(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(|unit| unit.to_be_bytes()).collect()
+  }
+
+  #[test]
+  fn bom_wins() {
+    assert_eq!(decode(b"\xEF\xBB\xBF[Script Info]", None), Ok(("[Script Info]".to_string(), UTF_8)));
+    let bytes = [b"\xFE\xFF".to_vec(), utf16be("[Script Info]")].concat();
+    assert_eq!(decode(&bytes, Some(SHIFT_JIS)), Ok(("[Script Info]".to_string(), UTF_16BE)));
+  }
+
+  #[test]
+  fn utf16_without_bom_is_sniffed() {
+    assert_eq!(decode(&utf16le("[Script Info]\n日本語"), None), Ok(("[Script Info]\n日本語".to_string(), UTF_16LE)));
+    assert_eq!(decode(&utf16be("[Script Info]"), None), Ok(("[Script Info]".to_string(), UTF_16BE)));
+  }
+
+  #[test]
+  fn odd_length_utf16_is_an_error() {
+    let mut bytes = utf16le("[Script Info]");
+    bytes.push(b'x');
+    assert_eq!(decode(&bytes, None), Err("Not valid UTF-16LE".to_string()));
+  }
+
+  #[test]
+  fn legacy_code_pages_need_an_encoding() {
+    let (bytes, _, _) = SHIFT_JIS.encode("日本語");
+    assert!(decode(&bytes, None).is_err());
+    assert_eq!(decode(&bytes, Some(SHIFT_JIS)), Ok(("日本語".to_string(), SHIFT_JIS)));
+    // Valid UTF-8 is never decoded with the legacy encoding
+    assert_eq!(decode("日本語".as_bytes(), Some(SHIFT_JIS)), Ok(("日本語".to_string(), UTF_8)));
+  }
+
+  #[test]
+  fn empty_files_are_utf8() {
+    assert_eq!(decode(b"", None), Ok((String::new(), UTF_8)));
+    assert_eq!(decode(b"a", None), Ok(("a".to_string(), UTF_8)));
+  }
+}
@@ -1,7 +1,7 @@
 use std;
 use std::fmt::{self, Display};
 
-use serde::{de, ser};
+use serde::{de, ser, Serialize};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -12,8 +12,90 @@ pub enum Error {
     MissingScriptInfo,
     MissingStylesInfo,
     MissingEvents,
-    Syntax,
-    FailedParsingFonts
+    FailedParsingFonts,
+    /// One of the other errors, caused by a specific line of the script
+    Located(Box<Error>, Location)
+}
+
+/// Where in a script something went wrong.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub file: Option<String>,
+    /// 1-based
+    pub line: usize,
+    /// 1-based, counted in characters
+    pub column: usize,
+    /// Number of characters to underline
+    pub length: usize,
+    pub section: Option<String>,
+    /// The whole offending line
+    pub text: String
+}
+
+/// A problem in a script that doesn't stop it from being processed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    pub message: String,
+    pub location: Location
+}
+
+impl Error {
+    pub fn at(self, location: Location) -> Error {
+        Error::Located(Box::new(self), location)
+    }
+
+    /// Records which file the error came from, if it points at a line.
+    pub fn in_file(self, file: &str) -> Error {
+        match self {
+            Error::Located(error, location) => Error::Located(error, location.in_file(file)),
+            error => error
+        }
+    }
+
+    /// The error message, followed by the offending line and a caret if it has a location.
+    pub fn render(&self) -> String {
+        match self {
+            Error::Located(error, location) => location.render("error", &error.to_string()),
+            error => format!("error: {}\n", error)
+        }
+    }
+}
+
+impl Location {
+    pub fn in_file(self, file: &str) -> Location {
+        Location { file: Some(file.to_string()), ..self }
+    }
+
+    /// Formats a diagnostic in the style of rustc:
+    ///
+    /// ```text
+    /// warning: event uses undefined style "Sign"
+    ///   --> ep01.ass:14:23 [Events]
+    ///    |
+    /// 14 | Dialogue: 0,0:00:01.00,0:00:02.00,Sign,,0,0,0,,Hello
+    ///    |                                   ^^^^
+    /// ```
+    pub fn render(&self, severity: &str, message: &str) -> String {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let file = self.file.as_deref().unwrap_or("<input>");
+        let section = self.section.as_ref().map(|s| format!(" [{}]", s)).unwrap_or_default();
+        let caret = format!("{}{}", " ".repeat(self.column.saturating_sub(1)), "^".repeat(self.length.max(1)));
+        format!(
+            "{}: {}\n{}--> {}:{}:{}{}\n{} |\n{} | {}\n{} | {}\n",
+            severity, message,
+            gutter, file, self.line, self.column, section,
+            gutter,
+            number, self.text,
+            gutter, caret
+        )
+    }
+}
+
+impl Warning {
+    pub fn render(&self) -> String {
+        self.location.render("warning", &self.message)
+    }
 }
 
 impl ser::Error for Error {
@@ -35,8 +117,8 @@ impl Display for Error {
             Error::MissingEvents => formatter.write_str("Missing events"),
             Error::MissingScriptInfo => formatter.write_str("Missing script info"),
             Error::MissingStylesInfo => formatter.write_str("Missing styles info"),
-            Error::Syntax => formatter.write_str("Invalid syntax"),
-            Error::FailedParsingFonts => formatter.write_str("Failed to parse font names")
+            Error::FailedParsingFonts => formatter.write_str("Failed to parse font names"),
+            Error::Located(error, location) => write!(formatter, "{} (line {})", error, location.line)
         }
     }
 }
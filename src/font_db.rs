@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::time::UNIX_EPOCH;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use fontconfig_sys::{
+  FcResultMatch, FcSetSystem, FcChar8, FcBool, FcConfig, FcPattern, FcFontSet, FcLangSet, FcLangDifferentLang, ffi_dispatch
+};
+
+#[cfg(not(feature = "dlopen"))]
+use fontconfig_sys::{
+  FcPatternGetString, FcConfigGetFonts, FcPatternGetBool, FcPatternGetInteger, FcPatternGetLangSet, FcLangSetHasLang,
+  FcConfigDestroy, FcConfigBuildFonts, FcInitLoadConfig, FcConfigGetFontDirs, FcStrListNext, FcStrListDone,
+};
+
+#[cfg(feature = "dlopen")]
+use fontconfig_sys::statics::LIB;
+
+use crate::ass_deserialize::MatchQuality;
+use crate::font_match::{normalize_name, CHARSET_LANGS};
+
+static FC_OUTLINE: &[u8] = b"outline\0";
+static FC_FULLNAME: &[u8] = b"fullname\0";
+static FC_FAMILY: &[u8] = b"family\0";
+static FC_FILE: &[u8] = b"file\0";
+static FC_WEIGHT: &[u8] = b"weight\0";
+static FC_SLANT: &[u8] = b"slant\0";
+static FC_WIDTH: &[u8] = b"width\0";
+static FC_POSTSCRIPT_NAME: &[u8] = b"postscriptname\0";
+static FC_LANG: &[u8] = b"lang\0";
+
+/// Bump whenever `FontEntry` changes, so stale caches get rebuilt instead of misread.
+const CACHE_VERSION: u32 = 2;
+
+/// One outline font known to fontconfig, with every name already run through `normalize_name`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FontEntry {
+  pub file: PathBuf,
+  pub families: Vec<String>,
+  pub fullnames: Vec<String>,
+  pub postscript_names: Vec<String>,
+  /// fontconfig weight, slant and width, not the OpenType scale
+  pub weight: i32,
+  pub slant: i32,
+  pub width: i32,
+  /// The languages of `CHARSET_LANGS` this font covers
+  pub langs: Vec<String>
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cache {
+  version: u32,
+  dirs: Vec<(String, u64)>,
+  entries: Vec<FontEntry>
+}
+
+/// Every system font, read from fontconfig once and indexed by name. It's plain data, so worker
+/// threads can share it without ever calling into fontconfig themselves.
+pub struct FontDb {
+  pub entries: Vec<FontEntry>,
+  names: HashMap<String, Vec<(usize, MatchQuality)>>
+}
+
+impl FontDb {
+  /// Reads the system fonts. With `use_cache` the index is stored on disk and reused as long as
+  /// none of fontconfig's font directories have been modified since.
+  pub fn load(use_cache: bool) -> FontDb {
+    unsafe {
+      let config: *mut FcConfig = ffi_dispatch!(LIB, FcInitLoadConfig,);
+      let entries = if use_cache {
+        let dirs = dir_stamps(config);
+        match read_cache(&dirs) {
+          Some(entries) => entries,
+          None => {
+            let entries = read_fonts(config);
+            write_cache(dirs, &entries);
+            entries
+          }
+        }
+      } else {
+        read_fonts(config)
+      };
+      ffi_dispatch!(LIB, FcConfigDestroy, config);
+      FontDb::new(entries)
+    }
+  }
+
+  /// Indexes `entries` by every one of their names.
+  pub fn new(entries: Vec<FontEntry>) -> FontDb {
+    let mut names: HashMap<String, Vec<(usize, MatchQuality)>> = HashMap::new();
+    for (index, entry) in entries.iter().enumerate() {
+      let mut seen: HashMap<&String, MatchQuality> = HashMap::new();
+      for (list, quality) in [(&entry.fullnames, MatchQuality::FullName), (&entry.families, MatchQuality::Family), (&entry.postscript_names, MatchQuality::PostScriptName)] {
+        for name in list {
+          seen.entry(name).or_insert(quality);
+        }
+      }
+      for (name, quality) in seen {
+        names.entry(name.clone()).or_default().push((index, quality));
+      }
+    }
+    for candidates in names.values_mut() {
+      candidates.sort();
+    }
+    FontDb { entries, names }
+  }
+
+  /// Fonts one of whose names equals the normalized `name`, in fontconfig's order.
+  pub fn candidates(&self, name: &str) -> Vec<(&FontEntry, MatchQuality)> {
+    match self.names.get(name) {
+      Some(candidates) => candidates.iter().map(|(index, quality)| (&self.entries[*index], *quality)).collect(),
+      None => vec![]
+    }
+  }
+
+  /// Up to `limit` fonts with names close to the normalized `name`, closest first, to suggest when
+  /// nothing matches exactly. Names are compared without spaces, hyphens and underscores.
+  pub fn similar(&self, name: &str, limit: usize) -> Vec<&FontEntry> {
+    let compact = |name: &str| name.chars().filter(|c| ! " -_".contains(*c)).collect::<Vec<char>>();
+    let wanted = compact(name);
+    let distance = |candidate: &String| {
+      let candidate = compact(candidate);
+      let contains = |a: &[char], b: &[char]| ! b.is_empty() && a.windows(b.len()).any(|w| w == b);
+      if contains(&candidate, &wanted) || contains(&wanted, &candidate) {
+        0
+      } else {
+        edit_distance(&wanted, &candidate)
+      }
+    };
+    // Closest name first, then the closest full name, so the regular face of a family comes first
+    let mut scored: Vec<((usize, usize), &FontEntry)> = vec![];
+    for entry in &self.entries {
+      let best = entry.families.iter().chain(&entry.fullnames).map(distance).min();
+      let full = entry.fullnames.iter().map(|n| edit_distance(&wanted, &compact(n))).min().unwrap_or(usize::MAX);
+      if let Some(best) = best.filter(|best| *best <= wanted.len() / 3) {
+        scored.push(((best, full), entry));
+      }
+    }
+    scored.sort_by_key(|(score, _)| *score);
+    let mut similar: Vec<&FontEntry> = vec![];
+    for (_, entry) in scored {
+      if similar.len() < limit && ! similar.iter().any(|e| e.file == entry.file) {
+        similar.push(entry);
+      }
+    }
+    similar
+  }
+
+  pub fn entry(&self, file: &Path) -> Option<&FontEntry> {
+    self.entries.iter().find(|entry| entry.file == file)
+  }
+}
+
+/// Levenshtein distance.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.iter().enumerate() {
+    let mut previous = row[0];
+    row[0] = i + 1;
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous + (ca != cb) as usize;
+      previous = row[j + 1];
+      row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+    }
+  }
+  row[b.len()]
+}
+
+fn read_fonts(config: *mut FcConfig) -> Vec<FontEntry> {
+  let mut entries: Vec<FontEntry> = vec![];
+  unsafe {
+    ffi_dispatch!(LIB, FcConfigBuildFonts, config);
+    let src: *mut FcFontSet = ffi_dispatch!(LIB, FcConfigGetFonts, config, FcSetSystem);
+    if src.is_null() {
+      return entries;
+    }
+    for i in 0..((*src).nfont as isize) {
+      let pattern: *mut FcPattern = *(*src).fonts.offset(i);
+      let mut value = 0;
+      let val: *mut FcBool = &mut value;
+
+      if ffi_dispatch!(LIB, FcPatternGetBool, pattern, FC_OUTLINE.as_ptr() as *mut c_char, 0, val) != FcResultMatch || *val != true as FcBool {
+        continue;
+      }
+
+      let file = match get_path(pattern, FC_FILE) {
+        Some(file) => file,
+        None => continue
+      };
+
+      entries.push(FontEntry {
+        file,
+        families: get_strings(pattern, FC_FAMILY).iter().map(|s| normalize_name(s)).collect(),
+        fullnames: get_strings(pattern, FC_FULLNAME).iter().map(|s| normalize_name(s)).collect(),
+        postscript_names: get_strings(pattern, FC_POSTSCRIPT_NAME).iter().map(|s| normalize_name(s)).collect(),
+        weight: get_integer(pattern, FC_WEIGHT).unwrap_or(80),
+        slant: get_integer(pattern, FC_SLANT).unwrap_or(0),
+        width: get_integer(pattern, FC_WIDTH).unwrap_or(100),
+        langs: CHARSET_LANGS.iter().filter(|(_, lang)| has_lang(pattern, lang)).map(|(_, lang)| lang.to_string()).collect()
+      });
+    }
+  }
+  entries
+}
+
+fn get_strings(pat: *mut FcPattern, field: &'static [u8]) -> Vec<String> {
+  let mut strings: Vec<String> = vec![];
+  unsafe {
+    let mut str: *mut FcChar8 = null_mut();
+    for index in 0.. {
+      if ffi_dispatch!(LIB, FcPatternGetString, pat, field.as_ptr() as *mut c_char, index, &mut str) != FcResultMatch {
+        break;
+      }
+      strings.push(String::from_utf8_lossy(CStr::from_ptr(str as *const c_char).to_bytes()).to_string());
+    }
+  }
+  strings
+}
+
+/// File names are kept as they are, even if they aren't valid UTF-8.
+fn get_path(pat: *mut FcPattern, field: &'static [u8]) -> Option<PathBuf> {
+  unsafe {
+    let mut str: *mut FcChar8 = null_mut();
+    if ffi_dispatch!(LIB, FcPatternGetString, pat, field.as_ptr() as *mut c_char, 0, &mut str) != FcResultMatch {
+      return None;
+    }
+    let bytes = CStr::from_ptr(str as *const c_char).to_bytes();
+    #[cfg(unix)]
+    let path = PathBuf::from(std::ffi::OsStr::from_bytes(bytes));
+    // fontconfig uses UTF-8 on Windows
+    #[cfg(not(unix))]
+    let path = PathBuf::from(String::from_utf8_lossy(bytes).to_string());
+    Some(path)
+  }
+}
+
+fn get_integer(pat: *mut FcPattern, field: &'static [u8]) -> Option<c_int> {
+  unsafe {
+    let mut value: c_int = 0;
+    if ffi_dispatch!(LIB, FcPatternGetInteger, pat, field.as_ptr() as *mut c_char, 0, &mut value) == FcResultMatch {
+      Some(value)
+    } else {
+      None
+    }
+  }
+}
+
+fn has_lang(pat: *mut FcPattern, lang: &str) -> bool {
+  let lang = CString::new(lang).unwrap();
+  unsafe {
+    let mut langset: *mut FcLangSet = null_mut();
+    if ffi_dispatch!(LIB, FcPatternGetLangSet, pat, FC_LANG.as_ptr() as *mut c_char, 0, &mut langset) != FcResultMatch {
+      return false;
+    }
+    ffi_dispatch!(LIB, FcLangSetHasLang, langset, lang.as_ptr() as *const FcChar8) != FcLangDifferentLang
+  }
+}
+
+/// Modification times of fontconfig's font directories and everything below them.
+fn dir_stamps(config: *mut FcConfig) -> Vec<(String, u64)> {
+  let mut roots: Vec<String> = vec![];
+  unsafe {
+    let list = ffi_dispatch!(LIB, FcConfigGetFontDirs, config);
+    if list.is_null() {
+      return vec![];
+    }
+    loop {
+      let dir = ffi_dispatch!(LIB, FcStrListNext, list);
+      if dir.is_null() {
+        break;
+      }
+      roots.push(String::from_utf8_lossy(CStr::from_ptr(dir as *const c_char).to_bytes()).to_string());
+    }
+    ffi_dispatch!(LIB, FcStrListDone, list);
+  }
+
+  let mut stamps: Vec<(String, u64)> = vec![];
+  for root in roots {
+    // a font directory that gets created later has to invalidate the cache as well
+    if ! Path::new(&root).exists() {
+      stamps.push((root, 0));
+      continue;
+    }
+    for dir in WalkDir::new(&root)
+      .follow_links(true)
+      .into_iter()
+      .filter_map(|d| d.ok())
+      .filter(|d| d.file_type().is_dir()) {
+      let mtime = dir.metadata().ok()
+        .and_then(|m| m.modified().ok())
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |m| m.as_secs());
+      stamps.push((dir.path().to_string_lossy().to_string(), mtime));
+    }
+  }
+  stamps.sort();
+  stamps
+}
+
+fn cache_path() -> Option<PathBuf> {
+  let base = if cfg!(windows) {
+    std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+  } else {
+    std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+      .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+  };
+  base.map(|base| base.join("fa_tool").join("fonts.json"))
+}
+
+fn read_cache(dirs: &Vec<(String, u64)>) -> Option<Vec<FontEntry>> {
+  let content = fs::read_to_string(cache_path()?).ok()?;
+  let cache: Cache = serde_json::from_str(&content).ok()?;
+  if cache.version == CACHE_VERSION && &cache.dirs == dirs {
+    Some(cache.entries)
+  } else {
+    None
+  }
+}
+
+fn write_cache(dirs: Vec<(String, u64)>, entries: &[FontEntry]) {
+  let path = match cache_path() {
+    Some(path) => path,
+    None => return
+  };
+  // Remembers the font directories the cache couldn't be written for, so the warning isn't repeated
+  // on every run until they change
+  let failed = path.with_file_name("fonts.failed");
+  let stamps = serde_json::to_string(&dirs).unwrap_or_default();
+  let cache = Cache {
+    version: CACHE_VERSION,
+    dirs,
+    entries: entries.to_vec()
+  };
+  // Fails for font paths that aren't valid UTF-8, those systems just don't get a cache
+  let written = serde_json::to_string(&cache).map_err(std::io::Error::other)
+    .and_then(|json| path.parent().map_or(Ok(()), fs::create_dir_all).map(|_| json))
+    .and_then(|json| fs::write(&path, json));
+  match written {
+    Ok(()) => {
+      let _ = fs::remove_file(&failed);
+    },
+    // stderr, so it doesn't end up in --format json output
+    Err(err) if fs::read_to_string(&failed).ok().as_ref() != Some(&stamps) => {
+      eprintln!("warning: failed to write the font cache \"{}\": {}", path.display(), err);
+      let _ = fs::write(&failed, stamps);
+    },
+    Err(_) => {}
+  }
+}
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::ass_deserialize::AssFont;
+use crate::font_match::normalize_name;
+
+/// Names of the mapping file, which is looked up in the folder of each subtitle file unless one is
+/// passed with `--map`. The first one is created by `check --interactive`.
+pub const MAP_FILES: [&str; 2] = ["fa_tool-fonts.toml", "fa_tool-fonts.json"];
+
+/// What a font is mapped to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Target {
+  /// A font file. Relative paths are relative to the mapping file.
+  File(PathBuf),
+  /// Another facename, which is looked up with the style of the original font
+  Font { font: String }
+}
+
+/// Font files or substitutes picked by hand for facenames, consulted before the font database.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FontMap {
+  /// Keys are a facename without `@`, which may contain `*` wildcards, optionally followed by a style
+  /// like "Facename:bold italic" or "Facename:700". Without a style the key is used for every style.
+  /// Fonts in scripts are only bold or not, so weights just tell which of the two a key is for.
+  #[serde(default)]
+  pub fonts: BTreeMap<String, Target>,
+  /// Keys of fonts that are left as they are without asking again
+  #[serde(default)]
+  pub skip: Vec<String>,
+  /// Where the map was read from and is saved to
+  #[serde(skip)]
+  pub path: PathBuf
+}
+
+impl FontMap {
+  /// Reads the mapping file at `path` as JSON if it ends in .json and as TOML otherwise, or starts an
+  /// empty one there if it doesn't exist.
+  pub fn load(path: &Path) -> Result<FontMap, String> {
+    let text = match fs::read_to_string(path) {
+      Ok(text) => text,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+      Err(err) => return Err(format!("{}: {}", path.display(), err))
+    };
+    let map: FontMap = match (is_json(path), text.trim().is_empty()) {
+      (_, true) => FontMap::default(),
+      (true, false) => serde_json::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))?,
+      (false, false) => toml::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))?
+    };
+    Ok(FontMap { path: path.to_path_buf(), ..map })
+  }
+
+  /// The mapping file given with `--map`, or else the one next to a subtitle file.
+  pub fn for_script(script: &Path, explicit: Option<&Path>) -> Result<FontMap, String> {
+    if let Some(path) = explicit {
+      return FontMap::load(path);
+    }
+    let dir = script.parent().unwrap_or(Path::new(""));
+    let path = MAP_FILES.iter().map(|name| dir.join(name)).find(|path| path.is_file());
+    FontMap::load(&path.unwrap_or(dir.join(MAP_FILES[0])))
+  }
+
+  pub fn save(&self) -> Result<(), String> {
+    let text = match is_json(&self.path) {
+      true => serde_json::to_string_pretty(self).map_err(|err| err.to_string()),
+      false => toml::to_string_pretty(self)
+        .map(|text| format!("# Fonts picked with `fa_tool check --interactive`, edit as needed.\n{}", text))
+        .map_err(|err| err.to_string())
+    };
+    let text = text.map_err(|err| format!("{}: {}", self.path.display(), err))?;
+    fs::write(&self.path, text).map_err(|err| format!("{}: {}", self.path.display(), err))
+  }
+
+  /// What a font is mapped to, with file paths made relative to the working directory. Names are
+  /// compared like fontconfig compares them, the best match wins, see `rank`.
+  pub fn lookup(&self, font: &AssFont) -> Option<Target> {
+    let mut best: Option<((bool, bool, usize), &Target)> = None;
+    for (key, target) in &self.fonts {
+      let Some(rank) = rank(key, font) else {
+        continue;
+      };
+      if best.as_ref().is_none_or(|(best, _)| rank > *best) {
+        best = Some((rank, target));
+      }
+    }
+    match best?.1 {
+      Target::File(path) => Some(Target::File(self.path.parent().unwrap_or(Path::new("")).join(path))),
+      target => Some(target.clone())
+    }
+  }
+
+  /// Whether a font was looked at before, so it isn't asked for again. Skipped keys match fonts
+  /// like the keys of `fonts` do.
+  pub fn decided(&self, font: &AssFont) -> bool {
+    self.lookup(font).is_some() || self.skip.iter().any(|key| rank(key, font).is_some())
+  }
+
+  /// Maps a font to `file`, stored relative to the mapping file if it is inside its folder.
+  pub fn set(&mut self, font: &AssFont, file: &Path) {
+    let file = fs::canonicalize(file).unwrap_or(file.to_path_buf());
+    let dir = self.path.parent().and_then(|dir| fs::canonicalize(dir).ok());
+    let file = match dir.as_ref().and_then(|dir| file.strip_prefix(dir).ok()) {
+      Some(relative) => relative.to_path_buf(),
+      None => file
+    };
+    self.fonts.insert(key(font), Target::File(file));
+  }
+
+  pub fn skip(&mut self, font: &AssFont) {
+    self.skip.push(key(font));
+  }
+}
+
+fn is_json(path: &Path) -> bool {
+  path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+/// The facename without `@`, vertical fonts are mapped like horizontal ones.
+fn plain(facename: &str) -> &str {
+  facename.strip_prefix('@').unwrap_or(facename)
+}
+
+/// The key for exactly the style of `font`, e.g. "Facename:regular" or "Facename:bold italic".
+fn key(font: &AssFont) -> String {
+  match (font.bold, font.italic) {
+    (false, false) => format!("{}:regular", plain(&font.facename)),
+    (true, false) => format!("{}:bold", plain(&font.facename)),
+    (false, true) => format!("{}:italic", plain(&font.facename)),
+    (true, true) => format!("{}:bold italic", plain(&font.facename))
+  }
+}
+
+/// How specifically `key` matches `font`, higher is better, or None if it doesn't match. Keys for the
+/// font's style win over ones without a style, and keys without wildcards over ones with them.
+fn rank(key: &str, font: &AssFont) -> Option<(bool, bool, usize)> {
+  let (pattern, style) = split_key(key);
+  let pattern = normalize_name(pattern);
+  if ! wildcard_match(&pattern, &normalize_name(plain(&font.facename))) || style.is_some_and(|style| style != (font.bold, font.italic)) {
+    return None;
+  }
+  // Longer patterns are more specific
+  Some((style.is_some(), ! pattern.contains('*'), pattern.len()))
+}
+
+/// Splits a key into the facename pattern and the bold and italic flags of its style, if it has one.
+/// The style is "bold", "regular" or a weight, optionally with "italic". Weights are reduced to the
+/// bold flag the way `\b` is: 700 and above is bold, anything lower regular, so "Facename:600" is
+/// the regular style.
+fn split_key(key: &str) -> (&str, Option<(bool, bool)>) {
+  let Some((name, style)) = key.rsplit_once(':') else {
+    return (key, None);
+  };
+  if style.trim().is_empty() {
+    return (key, None);
+  }
+  let (mut bold, mut italic) = (false, false);
+  for word in style.split_whitespace() {
+    match word {
+      "bold" => bold = true,
+      "regular" => bold = false,
+      "italic" => italic = true,
+      _ => match word.parse::<u32>() {
+        Ok(weight) => bold = weight >= 700,
+        // Not a style, the colon is part of the name
+        Err(_) => return (key, None)
+      }
+    }
+  }
+  (name, Some((bold, italic)))
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any number of characters.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+  let Some((first, rest)) = pattern.split_once('*') else {
+    return pattern == name;
+  };
+  let Some(mut remaining) = name.strip_prefix(first) else {
+    return false;
+  };
+  let mut parts: Vec<&str> = rest.split('*').collect();
+  let last = parts.pop().unwrap_or_default();
+  for part in parts {
+    match remaining.find(part) {
+      Some(index) => remaining = &remaining[index + part.len()..],
+      None => return false
+    }
+  }
+  remaining.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn font(facename: &str, bold: bool, italic: bool) -> AssFont {
+    AssFont::new(facename.to_string(), bold, italic, 1)
+  }
+
+  fn map(keys: &[&str]) -> FontMap {
+    FontMap {
+      fonts: keys.iter().map(|key| (key.to_string(), Target::File(PathBuf::from(key.replace([':', '*', ' '], "_"))))).collect(),
+      skip: vec![],
+      path: PathBuf::from("maps/fonts.toml")
+    }
+  }
+
+  fn mapped_to(map: &FontMap, font: &AssFont) -> Option<String> {
+    match map.lookup(font)? {
+      Target::File(path) => Some(path.strip_prefix("maps").unwrap().to_string_lossy().to_string()),
+      Target::Font { font } => Some(font)
+    }
+  }
+
+  #[test]
+  fn keys_split_into_name_and_style() {
+    assert_eq!(split_key("Foo"), ("Foo", None));
+    assert_eq!(split_key("Foo:bold"), ("Foo", Some((true, false))));
+    assert_eq!(split_key("Foo:regular italic"), ("Foo", Some((false, true))));
+    assert_eq!(split_key("Foo: 700  italic "), ("Foo", Some((true, true))));
+    // Weights below 700 are the regular style
+    assert_eq!(split_key("Foo:600"), ("Foo", Some((false, false))));
+    assert_eq!(split_key("Foo:700"), ("Foo", Some((true, false))));
+  }
+
+  #[test]
+  fn colons_that_arent_styles_stay_in_the_name() {
+    assert_eq!(split_key("Re:Zero"), ("Re:Zero", None));
+    assert_eq!(split_key("Foo:"), ("Foo:", None));
+    assert_eq!(split_key("A:B:bold"), ("A:B", Some((true, false))));
+    assert_eq!(split_key("Foo:bold semibold"), ("Foo:bold semibold", None));
+  }
+
+  #[test]
+  fn wildcards() {
+    assert!(wildcard_match("foo", "foo"));
+    assert!(! wildcard_match("foo", "foobar"));
+    assert!(wildcard_match("foo*", "foobar"));
+    assert!(wildcard_match("foo*", "foo"));
+    assert!(wildcard_match("*bar", "foobar"));
+    assert!(! wildcard_match("*bar", "barfoo"));
+    assert!(wildcard_match("*", ""));
+    assert!(wildcard_match("**", "anything"));
+    assert!(wildcard_match("a*b*c", "axxbyyc"));
+    assert!(! wildcard_match("a*b*c", "acb"));
+    // The start and the end can't share characters
+    assert!(! wildcard_match("ab*b", "ab"));
+    assert!(! wildcard_match("a*a", "a"));
+  }
+
+  #[test]
+  fn keys_are_written_for_one_style() {
+    assert_eq!(key(&font("Foo", false, false)), "Foo:regular");
+    assert_eq!(key(&font("Foo", true, true)), "Foo:bold italic");
+    assert_eq!(key(&font("@Foo", true, false)), "Foo:bold");
+    assert_eq!(split_key(&key(&font("Foo", false, true))), ("Foo", Some((false, true))));
+  }
+
+  #[test]
+  fn the_most_specific_key_wins() {
+    let map = map(&["Foo*", "Foo Sans", "Foo Sans:bold", "*"]);
+    assert_eq!(mapped_to(&map, &font("Foo Sans", true, false)).as_deref(), Some("Foo_Sans_bold"));
+    assert_eq!(mapped_to(&map, &font("foo sans", false, false)).as_deref(), Some("Foo_Sans"));
+    assert_eq!(mapped_to(&map, &font("@Foo Sans", false, true)).as_deref(), Some("Foo_Sans"));
+    assert_eq!(mapped_to(&map, &font("Foo Serif", false, false)).as_deref(), Some("Foo_"));
+    assert_eq!(mapped_to(&map, &font("Bar", false, false)).as_deref(), Some("_"));
+  }
+
+  #[test]
+  fn skips_match_like_mappings() {
+    let mut map = map(&[]);
+    map.skip(&font("Foo", false, false));
+    assert!(map.decided(&font("Foo", false, false)));
+    assert!(! map.decided(&font("Foo", true, false)));
+    map.skip.push("Bar*".to_string());
+    assert!(map.decided(&font("Bar Sans", true, true)));
+  }
+}
@@ -0,0 +1,294 @@
+use std::path::PathBuf;
+use std::vec;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::ass_deserialize::{AssFile, AssFont, MatchQuality};
+use crate::font_db::{FontDb, FontEntry};
+use crate::font_map::{FontMap, Target};
+use crate::sfnt::vertical_support;
+
+pub const NOT_FOUND: &str = "Nothing found.";
+
+/// Language fontconfig should cover for GDI charsets, as used by the ASS Encoding field. Coverage of
+/// each of them is recorded per font in the `FontDb`.
+pub const CHARSET_LANGS: [(&[u8], &str); 13] = [
+  (&[128], "ja"),
+  (&[129, 130], "ko"),
+  (&[134], "zh-cn"),
+  (&[136], "zh-tw"),
+  (&[161], "el"),
+  (&[162], "tr"),
+  (&[163], "vi"),
+  (&[177], "he"),
+  (&[178], "ar"),
+  (&[186], "lt"),
+  (&[204], "ru"),
+  (&[222], "th"),
+  (&[238], "pl")
+];
+
+/// Strategy used to pick a font file for a facename.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Matcher {
+  /// Translated from Aegisub's `font_file_lister_fontconfig.cpp`.
+  Aegisub,
+  /// Mirrors libass' `ass_fontselect.c`, which is what mpv and FFmpeg will render with.
+  Libass
+}
+
+impl Matcher {
+  pub const NAMES: [&'static str; 2] = ["aegisub", "libass"];
+
+  pub fn from_name(name: &str) -> Option<Matcher> {
+    match name {
+      "aegisub" => Some(Matcher::Aegisub),
+      "libass" => Some(Matcher::Libass),
+      _ => None
+    }
+  }
+}
+
+/// Looks up the file of every font, in `map` first if there is one. Fonts whose mapping can't be used
+/// stay unresolved with a `map_error` instead of being looked up by their own name.
+pub fn find_font_files(file: AssFile, db: &FontDb, matcher: Matcher, map: Option<&FontMap>) -> AssFile {
+  let mut fonts: Vec<AssFont> = vec![];
+  for font in &file.fonts {
+    let mut assfont = font.clone();
+    let found = match map.and_then(|map| map.lookup(font)) {
+      Some(Target::File(path)) if path.is_file() => Some((path, MatchQuality::Mapped)),
+      Some(Target::File(path)) => {
+        assfont.map_error = Some(format!("\"{}\" is mapped to {}, which doesn't exist", font.facename, path.display()));
+        None
+      },
+      Some(Target::Font { font: facename }) => {
+        let found = find_font(db, &AssFont { facename: facename.clone(), ..font.clone() }, matcher);
+        if found.is_none() {
+          assfont.map_error = Some(format!("\"{}\" is mapped to \"{}\", which wasn't found", font.facename, facename));
+        }
+        found.map(|(path, _)| (path, MatchQuality::Mapped))
+      },
+      None => find_font(db, font, matcher)
+    };
+    match found {
+      Some((path, quality)) => {
+        if assfont.vertical {
+          assfont.vertical_support = vertical_support(&path).ok();
+        }
+        assfont.quality = quality;
+        assfont.path = Some(path);
+      },
+      None => assfont.path = None
+    }
+    fonts.push(assfont);
+  };
+  AssFile { fonts, ..file }
+}
+
+/// Picks the file for one font. Vertical (`@`) fonts are looked up by their plain name.
+pub fn find_font(db: &FontDb, font: &AssFont, matcher: Matcher) -> Option<(PathBuf, MatchQuality)> {
+  let clear_facename: &str = font.facename.strip_prefix('@').unwrap_or(&font.facename);
+  let family = normalize_name(clear_facename);
+  match matcher {
+    Matcher::Aegisub => aegisub_find(db, &family, font),
+    Matcher::Libass => libass_find(db, &family, font)
+  }
+}
+
+/// Aegisub collects every font whose fullname or family equals the facename and lets `FcFontSetSort`
+/// pick one. This ranks the candidates in the order `FcFontSetSort` compares these properties:
+/// language coverage first, then slant, weight and width distance, keeping fontconfig's order on ties.
+/// Substitution rules from the user's fontconfig configuration are not applied to the request.
+fn aegisub_find(db: &FontDb, family: &str, font: &AssFont) -> Option<(PathBuf, MatchQuality)> {
+  // FC_WEIGHT_BOLD and FC_WEIGHT_REGULAR
+  let weight: i32 = if font.bold {
+    200
+  } else {
+    80
+  };
+
+  let slant: i32 = if font.italic {
+    110
+  } else {
+    0
+  };
+
+  let lang = charset_lang(font.encoding);
+  db.candidates(family).into_iter()
+    .min_by_key(|(entry, _)| (
+      lang.is_some_and(|lang| ! covers(entry, lang)),
+      (entry.slant - slant).abs(),
+      (entry.weight - weight).abs(),
+      (entry.width - 100).abs()
+    ))
+    .map(|(entry, quality)| (entry.file.clone(), quality))
+}
+
+/// Follows libass' `find_font`: every outline font whose family, fullname or PostScript name equals the
+/// requested name is a candidate, and the one whose weight and slant are closest to the request wins.
+/// libass ignores the style's Encoding, so unlike `aegisub_find` language coverage doesn't matter.
+fn libass_find(db: &FontDb, family: &str, font: &AssFont) -> Option<(PathBuf, MatchQuality)> {
+  let weight: i32 = if font.bold {
+    700
+  } else {
+    400
+  };
+
+  let slant: i32 = if font.italic {
+    100
+  } else {
+    0
+  };
+
+  db.candidates(family).into_iter()
+    .min_by_key(|(entry, _)| (weight_to_opentype(entry.weight) - weight).abs() + (entry.slant - slant).abs())
+    .map(|(entry, quality)| (entry.file.clone(), quality))
+}
+
+/// Folds a font name so that case, full-width forms and normalization differences don't matter.
+pub fn normalize_name(name: &str) -> String {
+  name.nfkc().collect::<String>().to_lowercase().nfkc().collect()
+}
+
+/// Language fontconfig should cover for a GDI charset, see `CHARSET_LANGS`.
+fn charset_lang(encoding: u8) -> Option<&'static str> {
+  CHARSET_LANGS.iter().find(|(charsets, _)| charsets.contains(&encoding)).map(|(_, lang)| *lang)
+}
+
+fn covers(entry: &FontEntry, lang: &str) -> bool {
+  entry.langs.iter().any(|l| l == lang)
+}
+
+/// Converts a fontconfig weight into the OpenType scale libass scores with.
+fn weight_to_opentype(weight: i32) -> i32 {
+  match weight {
+    w if w <= 20 => 100,
+    w if w <= 45 => 200,
+    w if w <= 62 => 300,
+    w if w <= 90 => 400,
+    w if w <= 140 => 500,
+    w if w <= 190 => 600,
+    w if w <= 202 => 700,
+    w if w <= 207 => 800,
+    _ => 900
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::ffi::{CStr, CString};
+  use std::os::raw::c_char;
+  use std::path::PathBuf;
+  use std::ptr::null_mut;
+
+  use fontconfig_sys::{FcChar8, FcFontSet, FcResult, FcResultMatch, ffi_dispatch};
+
+  #[cfg(not(feature = "dlopen"))]
+  use fontconfig_sys::{
+    FcConfigCreate, FcConfigDestroy, FcDefaultSubstitute, FcFontSetAdd, FcFontSetCreate, FcFontSetDestroy, FcFontSetSort,
+    FcLangSetAdd, FcLangSetCreate, FcLangSetDestroy, FcPatternAddBool, FcPatternAddInteger, FcPatternAddLangSet,
+    FcPatternAddString, FcPatternCreate, FcPatternDestroy, FcPatternGetString, FcWeightFromOpenType
+  };
+
+  #[cfg(feature = "dlopen")]
+  use fontconfig_sys::statics::LIB;
+
+  use super::*;
+
+  static FC_FAMILY: &[u8] = b"family\0";
+  static FC_FILE: &[u8] = b"file\0";
+  static FC_WEIGHT: &[u8] = b"weight\0";
+  static FC_SLANT: &[u8] = b"slant\0";
+  static FC_WIDTH: &[u8] = b"width\0";
+  static FC_OUTLINE: &[u8] = b"outline\0";
+  static FC_LANG: &[u8] = b"lang\0";
+
+  /// One family in every combination of weight, slant and width that used to be ranked differently,
+  /// plus a face covering Thai. Every face covers English, the usual locale fontconfig adds.
+  fn fixture() -> Vec<FontEntry> {
+    let entry = |file: &str, weight: i32, slant: i32, width: i32, langs: &[&str]| FontEntry {
+      file: PathBuf::from(file),
+      families: vec!["test sans".to_string()],
+      fullnames: vec![],
+      postscript_names: vec![],
+      weight,
+      slant,
+      width,
+      langs: langs.iter().map(|lang| lang.to_string()).collect()
+    };
+    vec![
+      entry("/fonts/Regular.ttf", 80, 0, 100, &["en"]),
+      entry("/fonts/BoldCondensed.ttf", 200, 0, 75, &["en"]),
+      entry("/fonts/Italic.ttf", 80, 100, 100, &["en"]),
+      entry("/fonts/BoldItalicExpanded.ttf", 200, 100, 125, &["en"]),
+      entry("/fonts/ThaiSemiCondensed.ttf", 80, 0, 87, &["en", "th"])
+    ]
+  }
+
+  /// What Aegisub's pipeline picks from `fonts`: the request after `FcDefaultSubstitute`, sorted
+  /// with `FcFontSetSort`.
+  fn fontconfig_pick(fonts: &[FontEntry], font: &AssFont) -> PathBuf {
+    let string = |s: &str| CString::new(s).unwrap();
+    unsafe {
+      let set = ffi_dispatch!(LIB, FcFontSetCreate,);
+      for entry in fonts {
+        let pattern = ffi_dispatch!(LIB, FcPatternCreate,);
+        let file = string(&entry.file.to_string_lossy());
+        let langs = ffi_dispatch!(LIB, FcLangSetCreate,);
+        for lang in &entry.langs {
+          ffi_dispatch!(LIB, FcLangSetAdd, langs, string(lang).as_ptr() as *const FcChar8);
+        }
+        ffi_dispatch!(LIB, FcPatternAddString, pattern, FC_FAMILY.as_ptr() as *const c_char, string(&entry.families[0]).as_ptr() as *const FcChar8);
+        ffi_dispatch!(LIB, FcPatternAddString, pattern, FC_FILE.as_ptr() as *const c_char, file.as_ptr() as *const FcChar8);
+        ffi_dispatch!(LIB, FcPatternAddInteger, pattern, FC_WEIGHT.as_ptr() as *const c_char, entry.weight);
+        ffi_dispatch!(LIB, FcPatternAddInteger, pattern, FC_SLANT.as_ptr() as *const c_char, entry.slant);
+        ffi_dispatch!(LIB, FcPatternAddInteger, pattern, FC_WIDTH.as_ptr() as *const c_char, entry.width);
+        ffi_dispatch!(LIB, FcPatternAddBool, pattern, FC_OUTLINE.as_ptr() as *const c_char, 1);
+        ffi_dispatch!(LIB, FcPatternAddLangSet, pattern, FC_LANG.as_ptr() as *const c_char, langs);
+        ffi_dispatch!(LIB, FcLangSetDestroy, langs);
+        ffi_dispatch!(LIB, FcFontSetAdd, set, pattern);
+      }
+
+      let pattern = ffi_dispatch!(LIB, FcPatternCreate,);
+      let weight = ffi_dispatch!(LIB, FcWeightFromOpenType, if font.bold { 700 } else { 400 });
+      ffi_dispatch!(LIB, FcPatternAddBool, pattern, FC_OUTLINE.as_ptr() as *const c_char, 1);
+      ffi_dispatch!(LIB, FcPatternAddInteger, pattern, FC_SLANT.as_ptr() as *const c_char, if font.italic { 110 } else { 0 });
+      ffi_dispatch!(LIB, FcPatternAddInteger, pattern, FC_WEIGHT.as_ptr() as *const c_char, weight);
+      if let Some(lang) = charset_lang(font.encoding) {
+        ffi_dispatch!(LIB, FcPatternAddString, pattern, FC_LANG.as_ptr() as *const c_char, string(lang).as_ptr() as *const FcChar8);
+      }
+      ffi_dispatch!(LIB, FcDefaultSubstitute, pattern);
+
+      let config = ffi_dispatch!(LIB, FcConfigCreate,);
+      let mut sets: *mut FcFontSet = set;
+      let mut result: FcResult = 0;
+      let sorted = ffi_dispatch!(LIB, FcFontSetSort, config, &mut sets, 1, pattern, 0, null_mut(), &mut result);
+      let mut file: *mut FcChar8 = null_mut();
+      assert_eq!(ffi_dispatch!(LIB, FcPatternGetString, *(*sorted).fonts, FC_FILE.as_ptr() as *const c_char, 0, &mut file), FcResultMatch);
+      let file = PathBuf::from(CStr::from_ptr(file as *const c_char).to_string_lossy().to_string());
+      ffi_dispatch!(LIB, FcFontSetDestroy, sorted);
+      ffi_dispatch!(LIB, FcFontSetDestroy, set);
+      ffi_dispatch!(LIB, FcPatternDestroy, pattern);
+      ffi_dispatch!(LIB, FcConfigDestroy, config);
+      file
+    }
+  }
+
+  #[test]
+  fn aegisub_ranks_like_fontconfig() {
+    let fonts = fixture();
+    let db = FontDb::new(fonts.clone());
+    for (bold, italic, encoding) in [(false, false, 1), (true, false, 1), (false, true, 1), (true, true, 1), (false, false, 222), (true, false, 222)] {
+      let font = AssFont::new("Test Sans".to_string(), bold, italic, encoding);
+      let (path, _) = aegisub_find(&db, "test sans", &font).unwrap();
+      assert_eq!(path, fontconfig_pick(&fonts, &font), "bold: {} italic: {} encoding: {}", bold, italic, encoding);
+    }
+  }
+
+  #[test]
+  fn libass_ignores_the_encoding() {
+    let db = FontDb::new(fixture());
+    let thai = AssFont::new("Test Sans".to_string(), false, false, 222);
+    assert_eq!(aegisub_find(&db, "test sans", &thai).unwrap().0, PathBuf::from("/fonts/ThaiSemiCondensed.ttf"));
+    assert_eq!(libass_find(&db, "test sans", &thai).unwrap().0, PathBuf::from("/fonts/Regular.ttf"));
+  }
+}
@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use clap::{Arg, ArgAction, ArgMatches};
+use encoding_rs::Encoding;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use walkdir::WalkDir;
+
+/// How folders given on the command line are searched for subtitle files.
+pub struct InputOptions {
+  /// When empty, only .ass and .ssa files are picked up
+  pub include: GlobSet,
+  pub has_include: bool,
+  pub exclude: GlobSet,
+  pub recursive: bool,
+  pub follow_symlinks: bool,
+  /// Code page for files that have no BOM and aren't UTF-8 or UTF-16
+  pub encoding: Option<&'static Encoding>
+}
+
+pub fn input_args() -> [Arg; 5] {
+  [
+    Arg::new("include")
+    .long("include")
+    .help("Only pick up files in folders matching this glob (replaces the default *.ass/*.ssa filter).")
+    .required(false)
+    .action(ArgAction::Append),
+    Arg::new("exclude")
+    .long("exclude")
+    .help("Skip files in folders matching this glob.")
+    .required(false)
+    .action(ArgAction::Append),
+    Arg::new("no-recursive")
+    .long("no-recursive")
+    .help("Don't descend into subfolders.")
+    .required(false)
+    .action(ArgAction::SetTrue),
+    Arg::new("follow-symlinks")
+    .long("follow-symlinks")
+    .help("Follow symbolic links to files and folders.")
+    .required(false)
+    .action(ArgAction::SetTrue),
+    Arg::new("encoding")
+    .long("encoding")
+    .help("Legacy code page for subtitle files that are neither UTF-8 nor UTF-16, e.g. shift_jis, gbk or windows-1252.")
+    .required(false)
+    .action(ArgAction::Set)
+  ]
+}
+
+impl InputOptions {
+  pub fn from_matches(matches: &ArgMatches) -> InputOptions {
+    let include = matches.get_many::<String>("include").map(|g| g.cloned().collect::<Vec<_>>()).unwrap_or_default();
+    let exclude = matches.get_many::<String>("exclude").map(|g| g.cloned().collect::<Vec<_>>()).unwrap_or_default();
+    let encoding = matches.get_one::<String>("encoding").map(|label| match Encoding::for_label(label.as_bytes()) {
+      Some(encoding) => encoding,
+      None => {
+        println!("Unknown encoding \"{}\"!", label);
+        exit(1);
+      }
+    });
+    InputOptions {
+      has_include: ! include.is_empty(),
+      include: build_globs(&include),
+      exclude: build_globs(&exclude),
+      recursive: ! matches.get_flag("no-recursive"),
+      follow_symlinks: matches.get_flag("follow-symlinks"),
+      encoding
+    }
+  }
+
+  fn wants(&self, path: &Path) -> bool {
+    let matches = |set: &GlobSet| set.is_match(path) || path.file_name().is_some_and(|name| set.is_match(name));
+    if matches(&self.exclude) {
+      return false;
+    }
+    if self.has_include {
+      return matches(&self.include);
+    }
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ass") || ext.eq_ignore_ascii_case("ssa"))
+  }
+}
+
+fn build_globs(patterns: &[String]) -> GlobSet {
+  let mut builder = GlobSetBuilder::new();
+  for pattern in patterns {
+    match Glob::new(pattern) {
+      Ok(glob) => {
+        builder.add(glob);
+      },
+      Err(err) => {
+        println!("Invalid pattern \"{}\": {}", pattern, err);
+        exit(1);
+      }
+    }
+  }
+  builder.build().unwrap()
+}
+
+/// Expands the command line into a list of subtitle files. Files named directly are always used,
+/// folders are filtered through `options`.
+pub fn to_file_list(input: Vec<PathBuf>, options: &InputOptions) -> Vec<PathBuf> {
+  let mut file_list: Vec<PathBuf> = vec![];
+  for path in input {
+    if ! path.exists() {
+      println!("\"{}\" does not exist!", path.display());
+      exit(1);
+    }
+    if path.is_file() {
+      file_list.push(path);
+      continue;
+    }
+    let mut walker = WalkDir::new(&path)
+      .follow_links(options.follow_symlinks)
+      .sort_by_file_name();
+    if ! options.recursive {
+      walker = walker.max_depth(1);
+    }
+    for f in walker
+      .into_iter()
+      .filter_map(|f| f.ok()) {
+      if f.file_type().is_file() && options.wants(f.path()) {
+        file_list.push(f.into_path());
+      };
+    }
+  };
+  file_list
+}
+
+/// Subtitle files that are muxed into one container.
+pub struct Group {
+  /// Path the output is named after: the file itself, or for `group_files` the folder joined with
+  /// the shared part of the file names
+  pub name: PathBuf,
+  pub files: Vec<PathBuf>
+}
+
+/// Puts every file into its own group, or with `by_stem` groups files in the same folder by the part
+/// of their name before the first dot, so ep01.en.ass and ep01.signs.ass both go into "ep01".
+pub fn group_files(files: Vec<PathBuf>, by_stem: bool) -> Vec<Group> {
+  let mut groups: Vec<Group> = vec![];
+  for file in files {
+    if ! by_stem {
+      groups.push(Group { name: file.clone(), files: vec![file] });
+      continue;
+    }
+    let base = match file.file_name().and_then(|name| name.to_str()) {
+      Some(name) => name.split('.').next().unwrap_or(name).into(),
+      None => file.file_stem().unwrap_or_default().to_os_string()
+    };
+    let name = file.with_file_name(base);
+    match groups.iter_mut().find(|g| g.name == name) {
+      Some(group) => group.files.push(file),
+      None => groups.push(Group { name, files: vec![file] })
+    }
+  }
+  groups
+}
@@ -0,0 +1,106 @@
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ass_deserialize::{AssFont, MatchQuality};
+use crate::font_db::FontDb;
+use crate::font_map::FontMap;
+use crate::font_match::normalize_name;
+use crate::report::FileRecord;
+
+/// fontconfig weight from which a font counts as bold (FC_WEIGHT_DEMIBOLD)
+const BOLD_WEIGHT: i32 = 180;
+
+/// Asks for a file for every font of `records` that wasn't found or only roughly matched, one question
+/// per font and mapping file, and saves the answers to the mapping file of each subtitle file.
+/// `files` are the subtitle files `records` were made from, `map` the file given with `--map`.
+/// Returns how many fonts were decided.
+pub fn resolve(files: &[PathBuf], records: &[FileRecord], db: &FontDb, map: Option<&Path>) -> Result<usize, String> {
+  let mut maps: Vec<(FontMap, bool)> = vec![];
+  let mut decided = 0;
+  let mut input = io::stdin().lock();
+
+  'files: for (file, record) in files.iter().zip(records) {
+    let map = FontMap::for_script(file, map)?;
+    let index = match maps.iter().position(|(m, _)| m.path == map.path) {
+      Some(index) => index,
+      None => {
+        maps.push((map, false));
+        maps.len() - 1
+      }
+    };
+    for font in &record.fonts {
+      let (map, changed) = &mut maps[index];
+      if map.decided(font) {
+        continue;
+      }
+      let Some(problem) = problem(font, db) else {
+        continue;
+      };
+      println!("{}: \"{}\" (b: {} i: {}) {}", record.name, font.facename, font.bold, font.italic, problem);
+      let facename = font.facename.strip_prefix('@').unwrap_or(&font.facename);
+      let suggestions = db.similar(&normalize_name(facename), 5);
+      for (number, entry) in suggestions.iter().enumerate() {
+        let name = entry.fullnames.first().or(entry.families.first()).map(|n| n.as_str()).unwrap_or_default();
+        println!("  {}) {}  ({})", number + 1, entry.file.display(), name);
+      }
+      loop {
+        print!("Pick a number or enter a path, or press Enter to skip and q to stop: ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        // End of input stops like q does
+        if input.read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+          println!();
+          break 'files;
+        }
+        let path = match line.trim() {
+          "" => {
+            map.skip(font);
+            *changed = true;
+            decided += 1;
+            break;
+          },
+          "q" => break 'files,
+          answer => match answer.parse::<usize>() {
+            Ok(number) if (1..=suggestions.len()).contains(&number) => suggestions[number - 1].file.clone(),
+            _ => PathBuf::from(answer)
+          }
+        };
+        if path.is_file() {
+          map.set(font, &path);
+          *changed = true;
+          decided += 1;
+          break;
+        }
+        println!("{} is not a file.", path.display());
+      }
+      println!();
+    }
+  }
+
+  for (map, _) in maps.iter().filter(|(_, changed)| *changed) {
+    map.save()?;
+    println!("Saved the decisions to {}", map.path.display());
+  }
+  Ok(decided)
+}
+
+/// Why a font needs a decision, if it does.
+fn problem(font: &AssFont, db: &FontDb) -> Option<String> {
+  let path = match (&font.path, font.quality) {
+    (_, MatchQuality::Mapped) => return None,
+    (None, _) | (_, MatchQuality::Unresolved) => return Some("was not found.".to_string()),
+    (Some(path), _) => path
+  };
+  let entry = db.entry(path)?;
+  let mut missing: Vec<&str> = vec![];
+  if font.bold != (entry.weight >= BOLD_WEIGHT) {
+    missing.push(if font.bold { "bold" } else { "regular weight" });
+  }
+  if font.italic != (entry.slant > 0) {
+    missing.push(if font.italic { "italic" } else { "upright" });
+  }
+  match missing.is_empty() {
+    true => None,
+    false => Some(format!("only matched {}, which isn't {}.", path.display(), missing.join(" or ")))
+  }
+}
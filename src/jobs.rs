@@ -0,0 +1,40 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Runs `work` for every index below `count` on up to `jobs` threads and hands the results to
+/// `report` on the calling thread, always in index order.
+pub fn run_ordered<T, W, R>(count: usize, jobs: usize, work: W, mut report: R)
+where
+  T: Send,
+  W: Fn(usize) -> T + Sync,
+  R: FnMut(usize, T)
+{
+  let next = AtomicUsize::new(0);
+  let (tx, rx) = mpsc::channel::<(usize, T)>();
+
+  thread::scope(|scope| {
+    for _ in 0..jobs.clamp(1, count.max(1)) {
+      let tx = tx.clone();
+      let (next, work) = (&next, &work);
+      scope.spawn(move || loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        if index >= count || tx.send((index, work(index))).is_err() {
+          break;
+        }
+      });
+    }
+    drop(tx);
+
+    let mut pending: BTreeMap<usize, T> = BTreeMap::new();
+    let mut expected = 0;
+    for (index, result) in rx {
+      pending.insert(index, result);
+      while let Some(result) = pending.remove(&expected) {
+        report(expected, result);
+        expected += 1;
+      }
+    }
+  });
+}
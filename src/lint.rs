@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use serde::Serialize;
+
+use crate::ass_deserialize::{refers_to, script_lines, AssFont, ScriptLine};
+use crate::error::Location;
+use crate::font_db::FontDb;
+use crate::font_match::{find_font, Matcher};
+use crate::override_tags::{tokenize, Segment};
+use crate::sfnt::vertical_support;
+
+/// How seriously a rule is taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+  Off,
+  Warning,
+  Error
+}
+
+/// Every rule with its default level and what it looks for, which `lint --help` lists.
+pub const RULES: [(&str, Level, &str); 7] = [
+  ("unused-style", Level::Warning, "style that no event or \\r tag uses"),
+  ("unrendered-font", Level::Warning, "style whose font every event replaces with \\fn"),
+  ("fn-whitespace", Level::Warning, "\\fn name with leading or trailing spaces"),
+  ("vertical-font", Level::Warning, "@ font without vertical metrics or vert substitutions"),
+  ("duplicate-style", Level::Warning, "style name that is defined more than once"),
+  ("unclosed-brace", Level::Error, "override block that is never closed"),
+  ("layout-res", Level::Warning, "LayoutResX/Y with another aspect ratio than PlayResX/Y")
+];
+
+pub struct LintConfig {
+  levels: HashMap<&'static str, Level>
+}
+
+impl LintConfig {
+  /// Applies `rule=off|warn|error` settings on top of the defaults in `RULES`.
+  pub fn new(settings: &[String]) -> Result<LintConfig, String> {
+    let mut levels: HashMap<&'static str, Level> = RULES.iter().map(|(name, level, _)| (*name, *level)).collect();
+    for setting in settings {
+      let (rule, level) = setting.split_once('=').ok_or_else(|| format!("Expected rule=level, got \"{}\"!", setting))?;
+      let rule = match RULES.iter().find(|(name, _, _)| *name == rule) {
+        Some((name, _, _)) => *name,
+        None => return Err(format!("Unknown lint rule \"{}\"!", rule))
+      };
+      let level = match level {
+        "off" => Level::Off,
+        "warn" => Level::Warning,
+        "error" => Level::Error,
+        _ => return Err(format!("Unknown level \"{}\", expected off, warn or error!", level))
+      };
+      levels.insert(rule, level);
+    }
+    Ok(LintConfig { levels })
+  }
+
+  pub fn level(&self, rule: &str) -> Level {
+    self.levels.get(rule).copied().unwrap_or(Level::Off)
+  }
+}
+
+/// One rule violation.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+  pub rule: &'static str,
+  pub level: Level,
+  pub message: String,
+  pub location: Location
+}
+
+impl Finding {
+  pub fn render(&self) -> String {
+    let severity = if self.level == Level::Error { "error" } else { "warning" };
+    self.location.render(&format!("{}[{}]", severity, self.rule), &self.message)
+  }
+}
+
+/// Lint results of one file.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LintRecord {
+  pub file: String,
+  pub error: Option<String>,
+  pub findings: Vec<Finding>
+}
+
+impl LintRecord {
+  pub fn count(&self, level: Level) -> usize {
+    self.findings.iter().filter(|f| f.level == level).count()
+  }
+}
+
+struct Style<'a> {
+  line: &'a ScriptLine,
+  /// Character column and text of the Name field
+  name: (usize, &'a str),
+  font: (usize, &'a str),
+  bold: bool,
+  italic: bool
+}
+
+struct Event<'a> {
+  line: &'a ScriptLine,
+  style: &'a str,
+  text: (usize, &'a str)
+}
+
+struct Linter<'a> {
+  config: &'a LintConfig,
+  findings: Vec<Finding>
+}
+
+impl Linter<'_> {
+  fn report(&mut self, rule: &'static str, message: String, location: Location) {
+    let level = self.config.level(rule);
+    if level != Level::Off {
+      self.findings.push(Finding { rule, level, message, location });
+    }
+  }
+}
+
+/// Runs every enabled rule over a script. `db` is only needed for `vertical-font`.
+pub fn lint(script: &str, db: Option<&FontDb>, matcher: Matcher, config: &LintConfig) -> Vec<Finding> {
+  let lines = script_lines(script);
+  let mut linter = Linter { config, findings: vec![] };
+
+  let styles: Vec<Style> = lines.iter()
+    .filter(|l| l.section.contains("Styles") && l.text.starts_with("Style:"))
+    .filter_map(|line| {
+      let fields = fields(line, 24);
+      if fields.len() < 9 {
+        return None;
+      }
+      Some(Style {
+        line,
+        name: fields[0],
+        font: fields[1],
+        bold: fields[7].1.trim() != "0",
+        italic: fields[8].1.trim() != "0"
+      })
+    })
+    .collect();
+  let events: Vec<Event> = lines.iter()
+    .filter(|l| l.section == "Events" && l.text.starts_with("Dialogue:"))
+    .filter_map(|line| {
+      let fields = fields(line, 10);
+      if fields.len() < 10 {
+        return None;
+      }
+      Some(Event { line, style: fields[3].1, text: fields[9] })
+    })
+    .collect();
+
+  // duplicate-style, libass keeps the last definition
+  let mut defined: HashMap<&str, usize> = HashMap::new();
+  for style in &styles {
+    if let Some(first) = defined.insert(style.name.1, style.line.number) {
+      let message = format!("style \"{}\" is already defined on line {}, only this definition is used", style.name.1, first);
+      linter.report("duplicate-style", message, style.line.location(style.name.0, style.name.1.chars().count()));
+    }
+  }
+
+  // Styles that are used at all, and whose font shows up somewhere
+  let mut used: Vec<&str> = vec![];
+  let mut rendered: Vec<&str> = vec![];
+  let mut vertical: Vec<(String, bool, bool, Location)> = vec![];
+  for style in &styles {
+    if style.font.1.starts_with('@') {
+      vertical.push((style.font.1.to_string(), style.bold, style.italic, style.line.location(style.font.0, style.font.1.chars().count())));
+    }
+  }
+  for event in &events {
+    let style = styles.iter().rev()
+      .find(|s| refers_to(event.style, s.name.1))
+      .map(|s| s.name.1)
+      .unwrap_or("Default");
+    used.push(style);
+
+    let text = tokenize(event.text.1);
+    if let Some(column) = text.unclosed {
+      linter.report("unclosed-brace", "override block is never closed, the rest of the line won't be rendered".to_string(), event.line.location(event.text.0 + column, 1));
+    }
+
+    // Follow which style's font is in use, None while a \fn replaces it
+    let mut base = style;
+    let mut current = Some(style);
+    let mut drawing = false;
+    for segment in &text.segments {
+      let Segment::Tags(tags) = segment else {
+        if segment.is_visible_text() && ! drawing {
+          rendered.extend(current);
+        }
+        continue;
+      };
+      for tag in tags {
+        let location = event.line.location(event.text.0 + tag.argument_column(), tag.argument.chars().count());
+        let argument = tag.argument.trim();
+        match tag.name {
+          "fn" => {
+            if argument != tag.argument && ! argument.is_empty() {
+              let message = format!("\\fn name \"{}\" has surrounding spaces, which not every renderer ignores", tag.argument);
+              linter.report("fn-whitespace", message, location.clone());
+            }
+            if argument.starts_with('@') {
+              vertical.push((argument.to_string(), false, false, location));
+            }
+            current = if argument.is_empty() { Some(base) } else { None };
+          },
+          "r" => {
+            base = styles.iter().rev()
+              .find(|s| ! argument.is_empty() && refers_to(argument, s.name.1))
+              .map(|s| s.name.1)
+              .unwrap_or(style);
+            used.push(base);
+            current = Some(base);
+          },
+          "p" => drawing = argument.parse::<i32>().is_ok_and(|scale| scale > 0),
+          _ => {}
+        }
+      }
+    }
+  }
+
+  for (index, style) in styles.iter().enumerate() {
+    // Report shadowed duplicates only once
+    if styles[index + 1..].iter().any(|s| s.name.1 == style.name.1) {
+      continue;
+    }
+    let location = style.line.location(style.name.0, style.name.1.chars().count());
+    if ! used.iter().any(|u| refers_to(u, style.name.1)) {
+      linter.report("unused-style", format!("style \"{}\" is never used", style.name.1), location);
+    } else if ! rendered.iter().any(|r| refers_to(r, style.name.1)) {
+      let message = format!("font \"{}\" of style \"{}\" is never rendered, events using it replace it with \\fn or have no text", style.font.1, style.name.1);
+      linter.report("unrendered-font", message, style.line.location(style.font.0, style.font.1.chars().count()));
+    }
+  }
+
+  if let Some(db) = db {
+    let mut checked: Vec<String> = vec![];
+    for (facename, bold, italic, location) in vertical {
+      if checked.contains(&facename) {
+        continue;
+      }
+      checked.push(facename.clone());
+      let font = AssFont::new(facename.clone(), bold, italic, 1);
+      let Some((path, _)) = find_font(db, &font, matcher) else {
+        continue;
+      };
+      let missing = vertical_support(&path).ok().and_then(|support| support.missing());
+      if let Some(missing) = missing {
+        let message = format!("vertical font \"{}\" matched {}, which has no {}", facename, path.display(), missing);
+        linter.report("vertical-font", message, location);
+      }
+    }
+  }
+
+  check_layout_res(&lines, &mut linter);
+  linter.findings.sort_by_key(|f| (f.location.line, f.location.column));
+  linter.findings
+}
+
+fn check_layout_res(lines: &[ScriptLine], linter: &mut Linter) {
+  let info = |key: &str| lines.iter().rev()
+    .filter(|l| l.section == "Script Info")
+    .find_map(|l| l.text.split_once(':').filter(|(k, _)| k.trim() == key).map(|(_, v)| (l, v.trim().parse::<u64>().ok())));
+  let (Some((layout_line, Some(layout_x))), Some((_, Some(layout_y)))) = (info("LayoutResX"), info("LayoutResY")) else {
+    return;
+  };
+  let (Some((_, Some(play_x))), Some((_, Some(play_y)))) = (info("PlayResX"), info("PlayResY")) else {
+    return;
+  };
+  // Widened, since the values come straight from the script
+  if layout_x as u128 * play_y as u128 != layout_y as u128 * play_x as u128 {
+    let message = format!("LayoutRes {}x{} doesn't have the aspect ratio of PlayRes {}x{}, text will be stretched", layout_x, layout_y, play_x, play_y);
+    linter.report("layout-res", message, layout_line.location(0, layout_line.text.chars().count()));
+  }
+}
+
+/// Splits the part after `Style:` or `Dialogue:` into at most `count` fields, each with the character
+/// column it starts at. Leading spaces are skipped except in the last field.
+fn fields(line: &ScriptLine, count: usize) -> Vec<(usize, &str)> {
+  let Some(colon) = line.text.find(':') else {
+    return vec![];
+  };
+  let mut start = colon + 1;
+  let mut fields = vec![];
+  for (index, field) in line.text[colon + 1..].splitn(count, ',').enumerate() {
+    let value = if index + 1 < count { field.trim_start() } else { field };
+    let offset = start + field.len() - value.len();
+    fields.push((line.text[..offset].chars().count(), value));
+    start += field.len() + 1;
+  }
+  fields
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::Path;
+
+  use crate::font_db::FontEntry;
+
+  use super::*;
+
+  const DEFAULT: &str = "Style: Default,Arial,20,&H0,&H0,&H0,&H0,0,0";
+
+  /// A script with one Script Info line, so styles start on line 5 and events on line 7 plus the
+  /// number of styles.
+  fn script(info: &str, styles: &[&str], events: &[&str]) -> String {
+    let events = events.iter().map(|text| format!("Dialogue: 0,0:00:00.00,0:00:01.00,{}", text)).collect::<Vec<_>>();
+    format!(
+      "[Script Info]\n{}\n[V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic\n\
+      {}\n[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n{}\n",
+      info, styles.join("\n"), events.join("\n")
+    )
+  }
+
+  /// Rule, level, line, column and length of every finding.
+  fn findings(script: &str, db: Option<&FontDb>, settings: &[&str]) -> Vec<(&'static str, Level, usize, usize, usize)> {
+    let settings = settings.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let config = LintConfig::new(&settings).unwrap();
+    lint(script, db, Matcher::Aegisub, &config).into_iter()
+      .map(|f| (f.rule, f.level, f.location.line, f.location.column, f.location.length))
+      .collect()
+  }
+
+  #[test]
+  fn rule_settings() {
+    let config = LintConfig::new(&[]).unwrap();
+    assert_eq!(config.level("unused-style"), Level::Warning);
+    assert_eq!(config.level("unclosed-brace"), Level::Error);
+    let config = LintConfig::new(&["unused-style=off".to_string(), "fn-whitespace=error".to_string(), "unclosed-brace=warn".to_string()]).unwrap();
+    assert_eq!(config.level("unused-style"), Level::Off);
+    assert_eq!(config.level("fn-whitespace"), Level::Error);
+    assert_eq!(config.level("unclosed-brace"), Level::Warning);
+    assert!(LintConfig::new(&["unused-style".to_string()]).is_err());
+    assert!(LintConfig::new(&["no-such-rule=off".to_string()]).is_err());
+    assert!(LintConfig::new(&["unused-style=loud".to_string()]).is_err());
+  }
+
+  #[test]
+  fn fields_keep_their_columns() {
+    let line = ScriptLine { number: 1, section: "Events".to_string(), text: "Dialogue: 0, 1,2,  Sign, text, with commas".to_string() };
+    assert_eq!(fields(&line, 5), vec![(10, "0"), (13, "1"), (15, "2"), (19, "Sign"), (24, " text, with commas")]);
+  }
+
+  #[test]
+  fn unused_and_duplicate_styles() {
+    let styles = [DEFAULT, "Style: Unused,Arial,20,&H0,&H0,&H0,&H0,0,0", "Style: Sign,Arial,20,&H0,&H0,&H0,&H0,0,0", "Style: Sign,Arial,20,&H0,&H0,&H0,&H0,0,0"];
+    let text = script("ScriptType: v4.00+", &styles, &["Default,,0,0,0,,Hi", "Sign,,0,0,0,,Hi"]);
+    assert_eq!(findings(&text, None, &[]), vec![("unused-style", Level::Warning, 6, 8, 6), ("duplicate-style", Level::Warning, 8, 8, 4)]);
+    assert_eq!(findings(&text, None, &["unused-style=off", "duplicate-style=error"]), vec![("duplicate-style", Level::Error, 8, 8, 4)]);
+  }
+
+  #[test]
+  fn unrendered_fonts_follow_fn_r_and_drawings() {
+    let styles = [DEFAULT, "Style: Sign,Comic Sans,20,&H0,&H0,&H0,&H0,0,0"];
+    let unrendered = ("unrendered-font", Level::Warning, 6, 13, 10);
+    // Every event replaces the font, or only draws
+    let text = script("ScriptType: v4.00+", &styles, &["Default,,0,0,0,,Hi", "Sign,,0,0,0,,{\\fnArial}Hi", "Sign,,0,0,0,,{\\p1}m 0 0 l 1 1"]);
+    assert_eq!(findings(&text, None, &[]), vec![unrendered]);
+    // \r and an empty \fn go back to the style's font, a drawing ends with \p0
+    for event in ["Sign,,0,0,0,,{\\fnArial\\r}Hi", "Sign,,0,0,0,,{\\fnArial}A{\\fn}B", "Default,,0,0,0,,{\\rSign}Hi", "Sign,,0,0,0,,{\\p1}m 0 0{\\p0}Hi"] {
+      let text = script("ScriptType: v4.00+", &styles, &["Default,,0,0,0,,Hi", event]);
+      assert_eq!(findings(&text, None, &[]), vec![], "{}", event);
+    }
+  }
+
+  #[test]
+  fn fn_whitespace_and_unclosed_braces() {
+    let text = script("ScriptType: v4.00+", &[DEFAULT], &["Default,,0,0,0,,{\\fn Arial }Hi", "Default,,0,0,0,,Hi {\\b1"]);
+    assert_eq!(findings(&text, None, &[]), vec![
+      ("fn-whitespace", Level::Warning, 8, 55, 7),
+      ("unclosed-brace", Level::Error, 9, 54, 1)
+    ]);
+  }
+
+  #[test]
+  fn layout_res_needs_the_aspect_ratio_of_play_res() {
+    let events = ["Default,,0,0,0,,Hi"];
+    let text = script("PlayResX: 1920\nPlayResY: 1080\nLayoutResX: 640\nLayoutResY: 360", &[DEFAULT], &events);
+    assert_eq!(findings(&text, None, &[]), vec![]);
+    let text = script("PlayResX: 1920\nPlayResY: 1080\nLayoutResX: 640\nLayoutResY: 480", &[DEFAULT], &events);
+    assert_eq!(findings(&text, None, &[]), vec![("layout-res", Level::Warning, 4, 1, 15)]);
+    // Values that would overflow u64 when multiplied
+    let text = script("PlayResX: 18446744073709551615\nPlayResY: 3\nLayoutResX: 18446744073709551615\nLayoutResY: 2", &[DEFAULT], &events);
+    assert_eq!(findings(&text, None, &[]).len(), 1);
+  }
+
+  #[test]
+  fn vertical_fonts_without_support() {
+    let font = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("FixtureSans-Regular.ttf");
+    let db = FontDb::new(vec![FontEntry {
+      file: font,
+      families: vec!["fixture sans".to_string()],
+      fullnames: vec![],
+      postscript_names: vec![],
+      weight: 80,
+      slant: 0,
+      width: 100,
+      langs: vec![]
+    }]);
+    let styles = [DEFAULT, "Style: Vert,@Fixture Sans,20,&H0,&H0,&H0,&H0,0,0"];
+    let text = script("ScriptType: v4.00+", &styles, &["Vert,,0,0,0,,Hi", "Default,,0,0,0,,{\\fn@Fixture Sans}Hi", "Default,,0,0,0,,Hi"]);
+    // Each font is only checked once, and not at all without a font database
+    assert_eq!(findings(&text, Some(&db), &[]), vec![("vertical-font", Level::Warning, 6, 13, 13)]);
+    assert_eq!(findings(&text, None, &[]), vec![]);
+  }
+}
@@ -1,30 +1,41 @@
 use std::{fs, vec};
-use std::path::Path;
-use std::ffi::{CStr, c_void};
-use std::os::raw::c_char;
-use std::ptr::null_mut;
-use std::process::{exit, ExitCode};
-use ass_deserialize::AssFont;
-use clap::{Arg, Command, ArgAction};
-use walkdir::WalkDir;
-
-use fontconfig_sys::{
-  FcMatchPattern, FcResultMatch, FcSetSystem, FcResult, FcChar8, FcBool, FcConfig, FcPattern, FcFontSet, ffi_dispatch
-};
-
-#[cfg(not(feature = "dlopen"))]
-use fontconfig_sys::{
-  FcConfigSubstitute, FcDefaultSubstitute, FcPatternAddBool, FcFontSetAdd, FcPatternDuplicate, FcPatternGetString, FcFontSetSort,  FcPatternDestroy, FcConfigDestroy,
-  FcPatternCreate, FcPatternAddInteger, FcConfigBuildFonts, FcInitLoadConfig, FcFontSetCreate, FcConfigGetFonts, FcPatternGetBool, FcFontSetDestroy, FcWeightFromOpenType,
-};
-
-#[cfg(feature = "dlopen")]
-use fontconfig_sys::statics::LIB;
+use std::io::IsTerminal;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use clap::{Arg, Command, ArgAction, value_parser};
+use encoding_rs::Encoding;
 
 pub mod ass_deserialize;
+pub mod decode;
 pub mod error;
-
-use crate::ass_deserialize::AssFile;
+pub mod font_db;
+pub mod font_map;
+pub mod font_match;
+pub mod input;
+pub mod interactive;
+pub mod jobs;
+pub mod lint;
+pub mod matroska;
+pub mod mux;
+pub mod output;
+pub mod override_tags;
+pub mod report;
+pub mod sfnt;
+pub mod track;
+
+use crate::ass_deserialize::{AssFile, MatchQuality};
+use crate::decode::decode;
+use crate::error::Warning;
+use crate::font_db::FontDb;
+use crate::font_map::FontMap;
+use crate::font_match::{find_font_files, Matcher};
+use crate::input::{group_files, input_args, to_file_list, Group, InputOptions};
+use crate::lint::{lint, Level, LintConfig, LintRecord, RULES};
+use crate::mux::{Existing, Muxer, Naming};
+use crate::output::{output_args, OutputOptions};
+use crate::report::{print_json, print_summary, FileRecord};
+use crate::track::{track_args, TrackOptions};
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() -> ExitCode {
@@ -44,16 +55,77 @@ fn main() -> ExitCode {
         .help("list of files or folders")
         .required(true)
         .action(ArgAction::Set)
+        .value_parser(value_parser!(PathBuf))
         .num_args(1..)
       )
+      .args(input_args())
+      .args(output_args())
+      .args(track_args())
+      .args(shared_args())
+      .mut_arg("format", |arg| arg.help("Print the results as human readable \"text\" or as \"json\", which includes the name of each font in the container."))
+      .arg(map_arg())
       .arg(
         Arg::new("force")
         .short('f')
         .long("force")
-        .help("Tell FFmpeg to overwrite already existent output-files.")
+        .help("Overwrite already existent output-files.")
+        .required(false)
+        .action(ArgAction::SetTrue)
+      )
+      .arg(
+        Arg::new("dry-run")
+        .short('n')
+        .long("dry-run")
+        .help("Print the output file, the attachments and the muxer command instead of running it.")
+        .required(false)
+        .action(ArgAction::SetTrue)
+      )
+      .arg(
+        Arg::new("group")
+        .short('g')
+        .long("group")
+        .help("Mux subtitle files of the same folder whose names start alike, e.g. ep01.en.ass and ep01.signs.ass, into one container named after that start.")
         .required(false)
         .action(ArgAction::SetTrue)
       )
+      .arg(
+        Arg::new("existing")
+        .long("existing")
+        .help("What to do with fonts attached to an output that --force overwrites: \"keep\" them over newly found versions, \"replace\" them (default) or also \"prune\" the ones the subtitles don't use.")
+        .required(false)
+        .action(ArgAction::Set)
+        .value_parser(Existing::NAMES)
+        .default_value("replace")
+      )
+      .arg(
+        Arg::new("attachment-names")
+        .long("attachment-names")
+        .help("How attachments are named in the container: \"original\" file name (default), \"family\" for Family-Style.ext from the font or \"hash\" for the SHA-256 of the file.")
+        .required(false)
+        .action(ArgAction::Set)
+        .value_parser(Naming::NAMES)
+        .default_value("original")
+      )
+      .arg(
+        Arg::new("placeholder")
+        .long("placeholder")
+        .help("Add a solid color video track of the script's PlayRes size until the last event ends, so players show the subtitles from 0:00. Takes an FFmpeg color like --placeholder=gray, black by default.")
+        .required(false)
+        .action(ArgAction::Set)
+        .num_args(0..=1)
+        .value_name("COLOR")
+        .require_equals(true)
+        .default_missing_value("black")
+      )
+      .arg(
+        Arg::new("muxer")
+        .long("muxer")
+        .help("Program that writes the containers: \"ffmpeg\" (default) or \"mkvmerge\" from MKVToolNix.")
+        .required(false)
+        .action(ArgAction::Set)
+        .value_parser(mux::MUXERS)
+        .default_value("ffmpeg")
+      )
     )
     .subcommand(
       Command::new("check")
@@ -65,325 +137,528 @@ fn main() -> ExitCode {
         .help("list of files or folders")
         .required(true)
         .action(ArgAction::Set)
+        .value_parser(value_parser!(PathBuf))
+        .num_args(1..)
+      )
+      .args(input_args())
+      .args(shared_args())
+      .arg(map_arg())
+      .arg(
+        Arg::new("interactive")
+        .short('i')
+        .long("interactive")
+        .help(format!("Ask for a file for every font that wasn't found or doesn't have the right style, and save the answers to the mapping file ({} next to the subtitle files by default).", font_map::MAP_FILES[0]))
+        .required(false)
+        .action(ArgAction::SetTrue)
+      )
+      .arg(
+        Arg::new("compare")
+        .long("compare")
+        .help("Run both matching strategies and only list fonts where they pick different files.")
+        .required(false)
+        .action(ArgAction::SetTrue)
+      )
+    )
+    .subcommand(
+      Command::new("lint")
+      .short_flag('l')
+      .long_flag("lint")
+      .about("Look for font related problems in subtitle files written in ASS.")
+      .arg(
+        Arg::new("file")
+        .help("list of files or folders")
+        .required(true)
+        .action(ArgAction::Set)
+        .value_parser(value_parser!(PathBuf))
         .num_args(1..)
       )
+      .args(input_args())
+      .args(shared_args())
+      .mut_arg("matcher", |arg| arg.help("Font matching strategy used to check vertical fonts: \"aegisub\" (default) or \"libass\"."))
+      .arg(
+        Arg::new("rule")
+        .long("rule")
+        .help(format!("Change how a rule is reported, e.g. \"unused-style=off\" (off, warn or error). Rules: {}", RULES.map(|(name, _, _)| name).join(", ")))
+        .long_help(format!(
+          "Change how a rule is reported, e.g. \"unused-style=off\" (off, warn or error). Rules:\n{}",
+          RULES.map(|(name, _, description)| format!("  {}: {}", name, description)).join("\n")
+        ))
+        .required(false)
+        .action(ArgAction::Append)
+      )
     )
   .get_matches();
   match matches.subcommand() {
     Some(("run", run_matches)) => {
       let force = run_matches.get_flag("force");
-      let args = run_matches.get_many::<String>("file");
-      let files = args.unwrap().map(|s| s.to_string()).collect::<Vec<_>>();
-      let raw_files = to_file_list(files);
-      let ass_files = deserialize(raw_files.clone());
-      unsafe {
-        // let config: *mut FcConfig = FcInitLoadConfig();
-        let config = ffi_dispatch!(LIB, FcInitLoadConfig,);
-        ffi_dispatch!(LIB, FcConfigBuildFonts, config);
-        // FcConfigBuildFonts(config);
-        for (file, name) in ass_files.iter().zip(raw_files.iter()) {
-          if let Err(err) = remux_this(find_font_files(file.clone(), config), name.clone(), force) {
-            println!("Error occurred for {}:\n  {}", name, err);
-            return ExitCode::FAILURE;
-          };
+      let dry_run = run_matches.get_flag("dry-run");
+      let matcher = Matcher::from_name(run_matches.get_one::<String>("matcher").unwrap()).unwrap();
+      let muxer = mux::muxer_from_name(run_matches.get_one::<String>("muxer").unwrap()).unwrap();
+      let placeholder = run_matches.get_one::<String>("placeholder").map(|c| c.as_str());
+      let existing = Existing::from_name(run_matches.get_one::<String>("existing").unwrap()).unwrap();
+      let naming = Naming::from_name(run_matches.get_one::<String>("attachment-names").unwrap()).unwrap();
+      let map = run_matches.get_one::<PathBuf>("map").cloned();
+      if let Some(map) = map.as_ref().filter(|m| ! m.is_file()) {
+        println!("The mapping file \"{}\" doesn't exist!", map.display());
+        return ExitCode::FAILURE;
+      }
+      let json = run_matches.get_one::<String>("format").unwrap() == "json";
+      let jobs = *run_matches.get_one::<usize>("jobs").unwrap();
+      let args = run_matches.get_many::<PathBuf>("file");
+      let files = args.unwrap().cloned().collect::<Vec<_>>();
+      let input_options = InputOptions::from_matches(run_matches);
+      let raw_files = to_file_list(files, &input_options);
+      let groups = group_files(raw_files, run_matches.get_flag("group"));
+      let output_options = OutputOptions::from_matches(run_matches);
+      let track_options = TrackOptions::from_matches(run_matches);
+      if placeholder.is_some() && ! muxer.generates_video() {
+        println!("--placeholder needs --muxer ffmpeg, {} can't generate a video track!", muxer.program());
+        return ExitCode::FAILURE;
+      }
+      if placeholder.is_some() && output_options.extension == "mks" {
+        println!("--placeholder can't be used with --container mks, which only holds subtitles!");
+        return ExitCode::FAILURE;
+      }
+      let names = groups.iter().map(|g| g.name.clone()).collect::<Vec<_>>();
+      let collisions = output_options.collisions(&names);
+      if ! collisions.is_empty() {
+        for (output, inputs) in collisions {
+          println!("\"{}\" would be written for each of:", output.display());
+          for input in inputs {
+            println!("  {}", input.display());
+          }
+        }
+        println!("Change the naming template with --name so that every file gets its own output.");
+        return ExitCode::FAILURE;
+      }
+      for group in &groups {
+        let output = output_options.output_path(&group.name);
+        if let Some(input) = group.files.iter().find(|f| **f == output) {
+          println!("\"{}\" would be overwritten by its own output!", input.display());
+          return ExitCode::FAILURE;
         }
-        ffi_dispatch!(LIB, FcConfigDestroy, config);
-        // FcConfigDestroy(config);
-        return ExitCode::SUCCESS;
       }
+      if let Some(dir) = output_options.dir.as_ref().filter(|_| ! dry_run) {
+        if let Err(err) = fs::create_dir_all(dir) {
+          println!("Failed to create \"{}\": {}", dir.display(), err);
+          return ExitCode::FAILURE;
+        }
+      }
+      let db = FontDb::load(run_matches.get_flag("cache"));
+      let context = RunContext {
+        db: &db,
+        matcher,
+        encoding: input_options.encoding,
+        output: &output_options,
+        track: &track_options,
+        muxer,
+        placeholder,
+        existing,
+        naming,
+        map: map.as_deref(),
+        force,
+        dry_run
+      };
+      let mut records: Vec<FileRecord> = vec![];
+      jobs::run_ordered(groups.len(), jobs, |index| {
+        run_group(&groups[index], &context)
+      }, |_, (log, mut group_records)| {
+        if ! json {
+          print!("{}", log);
+        }
+        records.append(&mut group_records);
+      });
+      if json {
+        print_json(&records);
+      } else {
+        print_summary(&records, ! dry_run);
+      }
+      if records.iter().any(|r| r.error.is_some()) {
+        return ExitCode::FAILURE;
+      }
+      ExitCode::SUCCESS
     },
     Some(("check", check_matches)) => {
-      let args = check_matches.get_many::<String>("file");
-      let files = args.unwrap().map(|s| s.to_string()).collect::<Vec<_>>();
-      let raw_files = to_file_list(files);
-      let ass_files = deserialize(raw_files.clone());
-      unsafe {
-        let config = ffi_dispatch!(LIB, FcInitLoadConfig,);
-        ffi_dispatch!(LIB, FcConfigBuildFonts, config);
-        for (file, name) in ass_files.iter().zip(raw_files.iter()) {
-          println!("{}:", name.clone());
-          for font_file in find_font_files(file.clone(), config).fonts.iter() {
-            println!("  {}      (b: {} i: {})       => {}", font_file.facename, font_file.bold, font_file.italic , font_file.path);
-          };
-          println!();
+      let matcher = Matcher::from_name(check_matches.get_one::<String>("matcher").unwrap()).unwrap();
+      let compare = check_matches.get_flag("compare");
+      let jobs = *check_matches.get_one::<usize>("jobs").unwrap();
+      let args = check_matches.get_many::<PathBuf>("file");
+      let files = args.unwrap().cloned().collect::<Vec<_>>();
+      let input_options = InputOptions::from_matches(check_matches);
+      let raw_files = to_file_list(files, &input_options);
+      let db = FontDb::load(check_matches.get_flag("cache"));
+      let json = check_matches.get_one::<String>("format").unwrap() == "json";
+      let interactive = check_matches.get_flag("interactive");
+      let map = check_matches.get_one::<PathBuf>("map").map(|m| m.as_path());
+      // check --interactive creates it
+      if let Some(map) = map.filter(|m| ! interactive && ! m.is_file()) {
+        println!("The mapping file \"{}\" doesn't exist!", map.display());
+        return ExitCode::FAILURE;
+      }
+      if interactive && (json || ! std::io::stdin().is_terminal() || ! std::io::stdout().is_terminal()) {
+        println!("--interactive needs a terminal and text output!");
+        return ExitCode::FAILURE;
+      }
+      let mut records: Vec<FileRecord> = vec![];
+      jobs::run_ordered(raw_files.len(), jobs, |index| {
+        check_file(&raw_files[index], input_options.encoding, &db, matcher, map, compare)
+      }, |_, (log, record)| {
+        if ! json {
+          print!("{}", log);
+        }
+        records.push(record);
+      });
+      if interactive {
+        match interactive::resolve(&raw_files, &records, &db, map) {
+          // The summary shows the fonts as they are matched now
+          Ok(decided) if decided > 0 => {
+            records = raw_files.iter().map(|path| check_file(path, input_options.encoding, &db, matcher, map, compare).1).collect();
+          },
+          Ok(_) => {},
+          Err(err) => {
+            println!("Failed to save the decisions: {}", err);
+            return ExitCode::FAILURE;
+          }
         }
-        ffi_dispatch!(LIB, FcConfigDestroy, config);
-        return ExitCode::SUCCESS;
       }
-    }
-    _ => unreachable!(),
-  }
-}
-
-static FC_OUTLINE: &[u8] = b"outline\0";
-static FC_FULLNAME: &[u8] = b"fullname\0";
-static FC_FAMILY: &[u8] = b"family\0";
-static FC_FILE: &[u8] = b"file\0";
-static FC_WEIGHT: &[u8] = b"weight\0";
-static FC_SLANT: &[u8] = b"slant\0";
-
-fn find_font_files(file: AssFile, config: *mut FcConfig) -> AssFile {
-  let mut fonts: Vec<AssFont> = vec![];
-  for font in &file.fonts {
-    let mut assfont = font.clone();
-    let clear_facename: &str = if font.facename.chars().nth(0).unwrap() == '@' {
-      &font.facename[1..]
-    } else {
-      &font.facename
-    };
-
-    let family = clear_facename.to_lowercase();
-
-    let weight: i32 = if font.bold {
-      700
-    } else {
-      400
-    };
-
-    let slant: i32 = if font.italic {
-      110
-    } else {
-      0
-    };
-
-    unsafe {
-      let pattern = ffi_dispatch!(LIB, FcPatternCreate,);
-      // let pattern = FcPatternCreate() as *mut FcPattern;
-      if pattern.is_null() {
-        continue;
+      if json {
+        print_json(&records);
+      } else {
+        print_summary(&records, false);
       }
-
-      ffi_dispatch!(LIB, FcPatternAddBool, pattern, FC_OUTLINE.as_ptr() as *mut c_char, true as FcBool);
-      ffi_dispatch!(LIB, FcPatternAddInteger, pattern, FC_SLANT.as_ptr() as *mut c_char, slant);
-      ffi_dispatch!(LIB, FcPatternAddInteger, pattern, FC_WEIGHT.as_ptr() as *mut c_char, ffi_dispatch!(LIB, FcWeightFromOpenType, weight));
-      // FcPatternAddBool(pattern, FC_OUTLINE.as_ptr() as *mut c_char, true as FcBool);
-      // FcPatternAddInteger(pattern, FC_SLANT.as_ptr() as *mut c_char, slant);
-      // FcPatternAddInteger(pattern, FC_WEIGHT.as_ptr() as *mut c_char, FcWeightFromOpenType(weight));
-      
-      ffi_dispatch!(LIB, FcDefaultSubstitute, pattern);
-      // FcDefaultSubstitute(pattern);
-      if ffi_dispatch!(LIB, FcConfigSubstitute, config, pattern, FcMatchPattern) != 1 {
-        continue;
+      if records.iter().any(|r| r.error.is_some()) {
+        return ExitCode::FAILURE;
       }
-      // if FcConfigSubstitute(config, pattern, FcMatchPattern) != 1 {
-      //   continue;
-      // }
-
-      let fset = ffi_dispatch!(LIB, FcFontSetCreate,);
-      fcfind(ffi_dispatch!(LIB, FcConfigGetFonts, config, FcSetSystem), fset, &family);
-      // let fset: *mut FcFontSet = FcFontSetCreate();
-      // // fcfind(FcConfigGetFonts(config, FcSetApplication), fset, &family);
-      // fcfind(FcConfigGetFonts(config, FcSetSystem), fset, &family);
-      
-      let result: *mut FcResult = &mut 0;
-      let mut sets: *mut FcFontSet = { fset };
-
-      let matches = ffi_dispatch!(LIB, FcFontSetSort, config, &mut sets, 1, pattern, false as FcBool, std::ptr::null_mut(), result);
-      // let matches: *mut FcFontSet = FcFontSetSort(config, &mut sets, 1, pattern, false as FcBool, std::ptr::null_mut(), result);
-      if (*matches).nfont == 0 {
-        assfont.path = "Nothing found.".to_string();
-        fonts.append(&mut vec![assfont]);
-        continue;
+      ExitCode::SUCCESS
+    },
+    Some(("lint", lint_matches)) => {
+      let settings = lint_matches.get_many::<String>("rule").map(|r| r.cloned().collect::<Vec<_>>()).unwrap_or_default();
+      let config = match LintConfig::new(&settings) {
+        Ok(config) => config,
+        Err(err) => {
+          println!("{}", err);
+          return ExitCode::FAILURE;
+        }
       };
-
-      ffi_dispatch!(LIB, FcFontSetDestroy, fset);
-      ffi_dispatch!(LIB, FcPatternDestroy, pattern);
-      // FcFontSetDestroy(fset);
-      // FcPatternDestroy(pattern);
-
-      let matching = *(*matches).fonts.offset(0);
-      
-      let mut file: *mut FcChar8 = &mut 0;
-      if ffi_dispatch!(LIB, FcPatternGetString, matching, FC_FILE.as_ptr() as *mut c_char, 0, &mut file) != FcResultMatch {
-        continue;
+      let matcher = Matcher::from_name(lint_matches.get_one::<String>("matcher").unwrap()).unwrap();
+      let jobs = *lint_matches.get_one::<usize>("jobs").unwrap();
+      let args = lint_matches.get_many::<PathBuf>("file");
+      let files = args.unwrap().cloned().collect::<Vec<_>>();
+      let input_options = InputOptions::from_matches(lint_matches);
+      let raw_files = to_file_list(files, &input_options);
+      // Fonts are only looked at for vertical font checks
+      let db = if config.level("vertical-font") != Level::Off {
+        Some(FontDb::load(lint_matches.get_flag("cache")))
+      } else {
+        None
+      };
+      let json = lint_matches.get_one::<String>("format").unwrap() == "json";
+      let mut records: Vec<LintRecord> = vec![];
+      jobs::run_ordered(raw_files.len(), jobs, |index| {
+        lint_file(&raw_files[index], input_options.encoding, db.as_ref(), matcher, &config)
+      }, |_, record| {
+        if ! json {
+          print_lint(&record);
+        }
+        records.push(record);
+      });
+      let errors: usize = records.iter().map(|r| r.count(Level::Error) + r.error.iter().count()).sum();
+      if json {
+        print_json(&records);
+      } else {
+        let warnings: usize = records.iter().map(|r| r.count(Level::Warning)).sum();
+        println!("{} files: {} errors, {} warnings.", records.len(), errors, warnings);
       }
-      // if FcPatternGetString(matching, FC_FILE.as_ptr() as *mut c_char, 0, &mut file) != FcResultMatch {
-      //   continue;
-      // }
-
-      assfont.path = std::str::from_utf8(CStr::from_ptr(file as *const c_char).to_bytes()).unwrap().to_owned();
-
-      if cfg!(windows) {
-        assfont.path = assfont.path.replace("/", "\\").replace("\\ ", " ");
+      if errors > 0 {
+        return ExitCode::FAILURE;
       }
+      ExitCode::SUCCESS
+    }
+    _ => unreachable!(),
+  }
+}
 
-      assfont.path = if assfont.path.contains(' ') {
-        "\"".to_owned() + &assfont.path + "\""
-      } else {
-        assfont.path
-      };
+/// Options every subcommand has: the matching strategy, the font cache, parallel jobs and the output
+/// format.
+fn shared_args() -> [Arg; 4] {
+  [
+    Arg::new("matcher")
+    .short('m')
+    .long("matcher")
+    .help("Font matching strategy: \"aegisub\" (default) or \"libass\" (same rules as mpv/FFmpeg).")
+    .required(false)
+    .action(ArgAction::Set)
+    .value_parser(Matcher::NAMES)
+    .default_value("aegisub"),
+    Arg::new("cache")
+    .long("cache")
+    .help("Keep the font index on disk and reuse it until a font directory changes.")
+    .required(false)
+    .action(ArgAction::SetTrue),
+    Arg::new("jobs")
+    .short('j')
+    .long("jobs")
+    .help("Number of subtitle files to process at the same time.")
+    .required(false)
+    .action(ArgAction::Set)
+    .value_parser(value_parser!(usize))
+    .default_value("1"),
+    Arg::new("format")
+    .long("format")
+    .help("Print the results as human readable \"text\" or as \"json\".")
+    .required(false)
+    .action(ArgAction::Set)
+    .value_parser(["text", "json"])
+    .default_value("text")
+  ]
+}
 
-      fonts.append(&mut vec![assfont]);
-      ffi_dispatch!(LIB, FcFontSetDestroy, matches);
-      // FcFontSetDestroy(matches);
-    }
-  };
-  AssFile { fonts }
+/// `--map`, for the subcommands that look up fonts.
+fn map_arg() -> Arg {
+  Arg::new("map")
+  .long("map")
+  .help(format!("Mapping file (TOML, or JSON if it ends in .json) that pins facenames to font files or other facenames, instead of {} next to the subtitle files.", font_map::MAP_FILES.join(" or ")))
+  .required(false)
+  .action(ArgAction::Set)
+  .value_parser(value_parser!(PathBuf))
 }
 
-fn fcfind(src: *mut FcFontSet, fset: *mut FcFontSet, family: &String) {
-  unsafe {       
-    for i in 0..((*src).nfont as isize) {
-      let pattern: *mut FcPattern = *(*src).fonts.offset(i);
-      let mut value = 0;
-      let val: *mut FcBool = &mut value;
-    
-      if ffi_dispatch!(LIB, FcPatternGetBool, pattern, FC_OUTLINE.as_ptr() as *mut c_char, 0, val) != FcResultMatch || *val != true as FcBool {
-        continue;
-      }
-      // if FcPatternGetBool(pattern, FC_OUTLINE.as_ptr() as *mut c_char, 0, val) != FcResultMatch || *val != true as FcBool {
-      //   continue;
-      // };
-      
-      if pattern_match(pattern, FC_FULLNAME, family) || pattern_match(pattern, FC_FAMILY, family) {
-        ffi_dispatch!(LIB, FcFontSetAdd, fset, ffi_dispatch!(LIB, FcPatternDuplicate, pattern));
-        // FcFontSetAdd(fset, FcPatternDuplicate(pattern));
-      };
+/// Resolves the fonts of one subtitle file and returns what should be printed for it.
+fn check_file(
+  path: &Path, encoding: Option<&'static Encoding>, db: &FontDb, matcher: Matcher, map: Option<&Path>, compare: bool
+) -> (String, FileRecord) {
+  let mut log = String::new();
+  let name = path.display();
+  let mut record = FileRecord::new(&name.to_string());
+  let file = match deserialize(path, encoding) {
+    Ok(file) => file,
+    Err(err) => {
+      write!(log, "Failed to deserialize \"{}\":\n{}", name, err).unwrap();
+      record.error = Some(err);
+      return (log, record);
     }
+  };
+  if file.text_encoding == "UTF-8" {
+    writeln!(log, "{}:", name).unwrap();
+  } else {
+    writeln!(log, "{} ({}):", name, file.text_encoding).unwrap();
   }
-}
-
-fn pattern_match(pat: *mut c_void, field: &'static [u8], name: &String) -> bool {
-  unsafe {
-    let mut str: *mut FcChar8 = null_mut();
-    for index in 0.. {
-      if ffi_dispatch!(LIB, FcPatternGetString, pat, field.as_ptr() as *mut c_char, index, &mut str) == FcResultMatch {
-      // if FcPatternGetString(pat, field.as_ptr() as *mut c_char, index, &mut str) == FcResultMatch {
-        let sstr: &String = &std::str::from_utf8(CStr::from_ptr(str as *const c_char).to_bytes()).unwrap().to_owned().to_lowercase();
-        if name == sstr {
-          return true;
-        }
-      } else {
-        return false;
+  for warning in &file.warnings {
+    log.push_str(&warning.render());
+  }
+  // A mapping file that can't be parsed fails the file instead of silently using the system fonts
+  let map = match load_map(path, map) {
+    Ok(map) => Some(map),
+    Err(err) => {
+      write!(log, "Failed to read the mapping file:\n{}\n", err).unwrap();
+      record.parsed = true;
+      record.error = Some(err);
+      return (log, record);
+    }
+  };
+  let found = find_font_files(file.clone(), db, matcher, map.as_ref());
+  record.set_file(&found);
+  // A mapping file entry that can't be used fails the file, so a typo doesn't go unnoticed
+  record.error = map_errors(&found);
+  if compare {
+    let aegisub = find_font_files(file.clone(), db, Matcher::Aegisub, map.as_ref());
+    let libass = find_font_files(file, db, Matcher::Libass, map.as_ref());
+    for (a, l) in aegisub.fonts.iter().zip(libass.fonts.iter()) {
+      if a.path != l.path {
+        writeln!(log, "  {}      (b: {} i: {})", a.facename, a.bold, a.italic).unwrap();
+        writeln!(log, "    aegisub => {}", a.path_display()).unwrap();
+        writeln!(log, "    libass  => {}", l.path_display()).unwrap();
       }
     }
-    false
+  } else {
+    for font_file in found.fonts.iter() {
+      if font_file.quality == MatchQuality::Unresolved {
+        writeln!(log, "  {}      (b: {} i: {})       => {}", font_file.facename, font_file.bold, font_file.italic , font_file.path_display()).unwrap();
+      } else {
+        writeln!(log, "  {}      (b: {} i: {})       => {}  [{}]", font_file.facename, font_file.bold, font_file.italic , font_file.path_display(), font_file.quality).unwrap();
+      }
+      if let Some(warning) = font_file.vertical_warning() {
+        writeln!(log, "    warning: {}", warning).unwrap();
+      }
+    };
   }
+  if let Some(err) = &record.error {
+    log.push_str(err);
+  }
+  writeln!(log).unwrap();
+  (log, record)
 }
 
-fn remux_this(file: AssFile, name: String, force: bool) -> Result<(), String> {
-  if ! force && Path::new(format!("{}.mkv", name).as_str()).exists() {
-    println!("{}.mkv already exists.", name);
-    return Ok(());
+fn lint_file(path: &Path, encoding: Option<&'static Encoding>, db: Option<&FontDb>, matcher: Matcher, config: &LintConfig) -> LintRecord {
+  let name = path.display().to_string();
+  let mut record = LintRecord { file: name.clone(), ..Default::default() };
+  match read_script(path, encoding) {
+    Ok((script, _)) => {
+      record.findings = lint(&script, db, matcher, config).into_iter()
+        .map(|f| lint::Finding { location: f.location.in_file(&name), ..f })
+        .collect();
+    },
+    Err(err) => record.error = Some(err)
   }
-  let mut duppl_check = String::new();
-  let mut args: Vec<&str> = vec![];
-  args.append(&mut vec!["-i"]);
-  let input = name.clone();
-  args.append(&mut vec![&input]);
-
-  let mut cmd = String::new();
-  let mut track_index = 1;
-  for assfont in file.fonts {
-    if duppl_check.contains(&assfont.path) {
-      continue
-    } else if assfont.path == "Nothing found." {
-      println!("\"{}\" could not be found on your system!", assfont.facename);
-      continue;
-    }
-    cmd = cmd.to_owned() + " -attach " + assfont.path.as_str();
+  record
+}
 
-    let path = assfont.path.replace("\"", "").to_lowercase();
-    if path.ends_with(".ttf") {
-      cmd = cmd.to_owned() + " -metadata:s:" + track_index.to_string().as_str() + " mimetype=application/x-truetype-font";
-    } else if path.ends_with(".otf") {
-      cmd = cmd.to_owned() + " -metadata:s:" + track_index.to_string().as_str() + " mimetype=application/x-font-opentype";
-    } else if path.ends_with(".ttc") {
-      cmd = cmd.to_owned() + " -metadata:s:" + track_index.to_string().as_str() + " mimetype=application/x-truetype-collection";
+/// Prints the findings of a file, or nothing if it is clean.
+fn print_lint(record: &LintRecord) {
+  if let Some(err) = &record.error {
+    print!("Failed to read \"{}\":\n{}", record.file, err);
+  } else if ! record.findings.is_empty() {
+    println!("{}:", record.file);
+    for finding in &record.findings {
+      print!("{}", finding.render());
     }
+    println!();
+  }
+}
 
-    let filename = if cfg!(windows) {
-      path.split('\\').last().unwrap()
-    } else {
-      path.split('/').last().unwrap()
-    };
-
-    cmd = cmd.to_owned() + " -metadata:s:" + track_index.to_string().as_str() + " filename=\"" + filename + "\"";
+/// What `run` does the same way for every group of subtitle files.
+struct RunContext<'a> {
+  db: &'a FontDb,
+  matcher: Matcher,
+  encoding: Option<&'static Encoding>,
+  output: &'a OutputOptions,
+  track: &'a TrackOptions,
+  muxer: &'a (dyn Muxer + Sync),
+  placeholder: Option<&'a str>,
+  existing: Existing,
+  naming: Naming,
+  /// Mapping file given with `--map`
+  map: Option<&'a Path>,
+  force: bool,
+  dry_run: bool
+}
 
-    track_index += 1;
-    duppl_check.push_str(&assfont.path);
+/// Reads every file of a group and muxes them into one container, unless one of them can't be read.
+fn run_group(group: &Group, context: &RunContext) -> (String, Vec<FileRecord>) {
+  let mut log = String::new();
+  let mut records: Vec<FileRecord> = vec![];
+  let mut files: Vec<(&Path, AssFile)> = vec![];
+  let map = match load_map(&group.files[0], context.map) {
+    Ok(map) => Some(map),
+    Err(err) => {
+      write!(log, "Failed to read the mapping file for {}:\n{}", group.name.display(), err).unwrap();
+      let records = group.files.iter()
+        .map(|path| FileRecord { error: Some(err.clone()), ..FileRecord::new(&path.display().to_string()) })
+        .collect();
+      return (log, records);
+    }
   };
-
-  let output = name + ".mkv";
-  cmd = cmd.trim().to_string();
-
-  if cmd.is_empty() {
-    return Err("None of the required fonts could be found!".to_string());
+  for path in &group.files {
+    let name = path.display();
+    let mut record = FileRecord::new(&name.to_string());
+    match deserialize(path, context.encoding) {
+      Ok(file) => {
+        for warning in &file.warnings {
+          log.push_str(&warning.render());
+        }
+        let file = find_font_files(file, context.db, context.matcher, map.as_ref());
+        for warning in file.fonts.iter().filter_map(|f| f.vertical_warning()) {
+          writeln!(log, "warning: {}", warning).unwrap();
+        }
+        record.set_file(&file);
+        match map_errors(&file) {
+          Some(err) => {
+            write!(log, "Failed to use the mapping file for \"{}\":\n{}", name, err).unwrap();
+            record.error = Some(err);
+          },
+          None => files.push((path, file))
+        }
+      },
+      Err(err) => {
+        write!(log, "Failed to deserialize \"{}\":\n{}", name, err).unwrap();
+        record.error = Some(err);
+      }
+    };
+    records.push(record);
   }
-
-  let vector_string = controlled_space_splitting(cmd);
-  let mut vector_str = vector_string.iter().map(|s| s.as_str()).collect();
-
-  args.append(&mut vector_str);
-  args.append(&mut vec![&output]);
-  
-  if force {
-    args.append(&mut vec!["-y"])
-  } else {
-    args.append(&mut vec!["-n"]);
+  if files.len() < group.files.len() {
+    if group.files.len() > 1 {
+      writeln!(log, "Skipped {} because not all of its files could be read.", group.name.display()).unwrap();
+    }
+    return (log, records);
   }
 
-  let result = std::process::Command::new("ffmpeg").args(args).output().unwrap();
-  match result.status.success() {
-    true => Ok(()),
-    false => {
-      Err(String::from_utf8(result.stderr).unwrap())
+  let output = context.output.output_path(&group.name);
+  match remux_this(&files, output, context, &mut log, &mut records) {
+    Ok(muxed) => records.iter_mut().for_each(|r| r.muxed = muxed),
+    Err(err) => {
+      writeln!(log, "Error occurred for {}:\n  {}", group.name.display(), err).unwrap();
+      records.iter_mut().for_each(|r| r.error = Some(err.clone()));
     }
   }
+  (log, records)
 }
 
-fn deserialize(files: Vec<String>) -> Vec<AssFile> {
-  let mut deserialized_files: Vec<AssFile> = vec![];
-  for x in files {
-    let file = fs::read_to_string(&x).unwrap();
-    let ass: Result<AssFile, error::Error> = AssFile::get_fonts(file);
-    if ass.is_ok() {
-      deserialized_files.append(&mut vec![ass.unwrap()]);
-    } else {
-      println!("Failed to deserialize: \"{}\".", x);
+/// Returns whether a file was written, which isn't the case if the output already exists or on a dry run.
+/// The fonts of `records` get the names their files have in the container.
+fn remux_this(
+  files: &[(&Path, AssFile)], output: PathBuf, context: &RunContext, log: &mut String, records: &mut [FileRecord]
+) -> Result<bool, String> {
+  if ! context.force && output.exists() {
+    writeln!(log, "{} already exists.", output.display()).unwrap();
+    return Ok(false);
+  }
+  let inputs = files.iter().map(|(input, _)| *input).collect::<Vec<_>>();
+  let tracks = files.iter().zip(context.track.for_group(&inputs))
+    .map(|((input, file), options)| mux::Track { input, file, options })
+    .collect::<Vec<_>>();
+  let mut attached = vec![];
+  if output.exists() {
+    match matroska::attachments(&output) {
+      Ok(files) => attached = files,
+      Err(err) => writeln!(log, "Can't read the attachments of the existing output, they will be lost: {}", err).unwrap()
     }
-  };
-  deserialized_files
+  }
+  let plan = mux::plan(&tracks, &output, context.force, context.placeholder, (&attached, context.existing), context.naming, context.muxer)?;
+  for font in records.iter_mut().flat_map(|r| &mut r.fonts) {
+    font.attachment = font.path.as_deref().and_then(|path| plan.attachment_name(path)).map(|n| n.to_string_lossy().to_string());
+  }
+  if context.dry_run {
+    let inputs = files.iter().map(|(input, _)| input.display().to_string()).collect::<Vec<_>>();
+    writeln!(log, "{}:", inputs.join(", ")).unwrap();
+    plan.describe(log);
+    return Ok(false);
+  }
+  for facename in &plan.missing {
+    writeln!(log, "\"{}\" could not be found on your system!", facename).unwrap();
+  }
+  plan.execute(context.muxer)?;
+  for change in &plan.changes {
+    writeln!(log, "{}: {}", output.display(), change).unwrap();
+  }
+  Ok(true)
 }
 
-fn controlled_space_splitting(input: String) -> Vec<String> {
-  let mut output: Vec<String> = vec![];
-  let mut temp: String = String::new();
-  let mut quotations = false;
-  for ch in input.chars() {
-    if ch == '\"' {
-      quotations = !quotations;
-    } else if ch == ' ' && ! quotations {
-      if !temp.is_empty() {
-        output.push(temp.clone());
-        temp.clear();
-      }
-    } else {
-      temp.push(ch);
-    }
-  }
+/// The mapping file of a subtitle file. Errors come back already rendered, like `map_errors`.
+fn load_map(path: &Path, explicit: Option<&Path>) -> Result<FontMap, String> {
+  FontMap::for_script(path, explicit).map_err(|err| format!("error: {}\n", err.trim_end()))
+}
 
-  if !temp.is_empty() {
-    output.push(temp);
-  }
+/// The mapping file entries that couldn't be used for the fonts of a file, rendered like errors.
+fn map_errors(file: &AssFile) -> Option<String> {
+  let errors = file.fonts.iter().filter_map(|f| f.map_error.as_ref()).map(|err| format!("error: {}\n", err)).collect::<String>();
+  (! errors.is_empty()).then_some(errors)
+}
 
-  output
+/// Reads and parses one subtitle file. Errors come back already rendered as a diagnostic.
+fn deserialize(path: &Path, encoding: Option<&'static Encoding>) -> Result<AssFile, String> {
+  let name = path.display().to_string();
+  let (file, encoding) = read_script(path, encoding)?;
+  let ass: Result<AssFile, error::Error> = AssFile::get_fonts(file);
+  ass.map(|ass| AssFile {
+    text_encoding: encoding.name().to_string(),
+    warnings: ass.warnings.into_iter().map(|w| Warning { location: w.location.in_file(&name), ..w }).collect(),
+    ..ass
+  }).map_err(|err| err.in_file(&name).render())
 }
 
-fn to_file_list(input: Vec<String>) -> Vec<String> {
-  let mut file_list: Vec<String> = vec![];
-  for x in input {
-    if ! Path::new(&x).exists() {
-      println!("\"{}\" does not exist!", x);
-      exit(1);
-    }
-    for f in WalkDir::new(&x)
-      .into_iter()
-      .filter_map(|f| f.ok()) {
-      if f.metadata().unwrap().is_file() {
-        file_list.append(&mut vec![f.path().to_string_lossy().to_string()])
-      };
-    }
-  };
-  file_list
+/// Reads a subtitle file into text, see `decode`.
+fn read_script(path: &Path, encoding: Option<&'static Encoding>) -> Result<(String, &'static Encoding), String> {
+  let bytes = fs::read(path).map_err(|err| format!("error: {}\n", err))?;
+  decode(&bytes, encoding).map_err(|err| format!("error: {}\n", err))
 }
@@ -0,0 +1,235 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+const EBML: u64 = 0x1A45DFA3;
+const SEGMENT: u64 = 0x18538067;
+const ATTACHMENTS: u64 = 0x1941A469;
+const ATTACHED_FILE: u64 = 0x61A7;
+const FILE_NAME: u64 = 0x466E;
+const FILE_MIME_TYPE: u64 = 0x4660;
+const FILE_DATA: u64 = 0x465C;
+
+/// A file attached to a Matroska container.
+pub struct AttachedFile {
+  pub filename: String,
+  pub mimetype: String,
+  pub data: Vec<u8>
+}
+
+/// Reads the attachments of a Matroska file. Clusters are skipped without reading them, so this stays
+/// fast for files with video.
+pub fn attachments(path: &Path) -> Result<Vec<AttachedFile>, String> {
+  let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+  let length = file.metadata().map_err(|err| format!("{}: {}", path.display(), err))?.len();
+  let mut reader = BufReader::new(file);
+  let invalid = || format!("{}: not a Matroska file", path.display());
+
+  match element_header(&mut reader) {
+    Some((EBML, Some(size))) => reader.seek_relative(i64::try_from(size).map_err(|_| invalid())?).map_err(|_| invalid())?,
+    _ => return Err(invalid())
+  }
+  let end = match element_header(&mut reader) {
+    Some((SEGMENT, size)) => {
+      let start = reader.stream_position().map_err(|_| invalid())?;
+      size.map_or(length, |size| (start + size).min(length))
+    },
+    _ => return Err(invalid())
+  };
+
+  let mut files = vec![];
+  while reader.stream_position().is_ok_and(|position| position < end) {
+    // Live recordings can have clusters of unknown size, nothing after them can be found cheaply
+    let Some((id, Some(size))) = element_header(&mut reader) else {
+      break;
+    };
+    if id == ATTACHMENTS {
+      // A broken size must not make us allocate more than the file could hold. The header itself can
+      // already reach past a Segment that claims to be too small.
+      let position = reader.stream_position().map_err(|_| invalid())?;
+      if size > end.saturating_sub(position) {
+        return Err(invalid());
+      }
+      let mut data = vec![0; size as usize];
+      reader.read_exact(&mut data).map_err(|_| invalid())?;
+      files.extend(attached_files(&data));
+    } else if i64::try_from(size).map_or(true, |size| reader.seek_relative(size).is_err()) {
+      break;
+    }
+  }
+  Ok(files)
+}
+
+/// The `AttachedFile` elements of an `Attachments` element.
+fn attached_files(data: &[u8]) -> Vec<AttachedFile> {
+  let mut files = vec![];
+  for (id, content) in children(data) {
+    if id != ATTACHED_FILE {
+      continue;
+    }
+    let mut file = AttachedFile { filename: String::new(), mimetype: String::new(), data: vec![] };
+    for (id, value) in children(content) {
+      match id {
+        FILE_NAME => file.filename = String::from_utf8_lossy(value).to_string(),
+        FILE_MIME_TYPE => file.mimetype = String::from_utf8_lossy(value).to_string(),
+        FILE_DATA => file.data = value.to_vec(),
+        _ => {}
+      }
+    }
+    files.push(file);
+  }
+  files
+}
+
+/// ID and content of the elements in `data`, up to the first one that doesn't fit.
+fn children(data: &[u8]) -> Vec<(u64, &[u8])> {
+  let mut elements = vec![];
+  let mut offset = 0;
+  while let Some((id, id_length)) = vint(&data[offset..], true) {
+    let Some((size, size_length)) = vint(&data[offset + id_length..], false) else {
+      break;
+    };
+    let Some(size) = known(size, size_length) else {
+      break;
+    };
+    let start = offset + id_length + size_length;
+    let Some(content) = usize::try_from(size).ok().and_then(|size| data.get(start..start.checked_add(size)?)) else {
+      break;
+    };
+    elements.push((id, content));
+    offset = start + content.len();
+  }
+  elements
+}
+
+/// ID and size of the element at the reader's position. The size is None if it is unknown.
+fn element_header(reader: &mut impl Read) -> Option<(u64, Option<u64>)> {
+  let mut bytes = vec![];
+  let (id, _) = read_vint(reader, &mut bytes, true)?;
+  bytes.clear();
+  let (size, length) = read_vint(reader, &mut bytes, false)?;
+  Some((id, known(size, length)))
+}
+
+fn read_vint(reader: &mut impl Read, bytes: &mut Vec<u8>, keep_marker: bool) -> Option<(u64, usize)> {
+  let mut first = [0];
+  reader.read_exact(&mut first).ok()?;
+  let length = first[0].leading_zeros() as usize + 1;
+  bytes.push(first[0]);
+  bytes.resize(length.min(8), 0);
+  reader.read_exact(&mut bytes[1..]).ok()?;
+  vint(bytes, keep_marker)
+}
+
+/// Decodes a variable size integer, returning it with its length. IDs keep their length marker.
+fn vint(data: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+  let first = *data.first()?;
+  let length = first.leading_zeros() as usize + 1;
+  if length > 8 {
+    return None;
+  }
+  let mut value = if keep_marker { first as u64 } else { (first as u64) & (0xFF >> length) };
+  for byte in data.get(1..length)? {
+    value = value << 8 | *byte as u64;
+  }
+  Some((value, length))
+}
+
+/// A size with all value bits set means the size is unknown.
+fn known(size: u64, length: usize) -> Option<u64> {
+  (size != (1 << (7 * length)) - 1).then_some(size)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::path::PathBuf;
+
+  use super::*;
+
+  /// An element with an 8 byte size, which is valid for any content.
+  fn element(id: u64, content: &[u8]) -> Vec<u8> {
+    let id = id.to_be_bytes();
+    let id = &id[id.iter().position(|b| *b != 0).unwrap()..];
+    [id, &[0x01], &(content.len() as u64).to_be_bytes()[1..], content].concat()
+  }
+
+  fn attached_file(name: &str, mimetype: &str, data: &[u8]) -> Vec<u8> {
+    element(ATTACHED_FILE, &[element(FILE_NAME, name.as_bytes()), element(FILE_MIME_TYPE, mimetype.as_bytes()), element(FILE_DATA, data)].concat())
+  }
+
+  fn write(name: &str, data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("fa_tool-matroska-{}-{}.mkv", std::process::id(), name));
+    fs::write(&path, data).unwrap();
+    path
+  }
+
+  #[test]
+  fn vints() {
+    assert_eq!(vint(&[0x81], false), Some((1, 1)));
+    assert_eq!(vint(&[0x40, 0x02], false), Some((2, 2)));
+    assert_eq!(vint(&[0x1A, 0x45, 0xDF, 0xA3], true), Some((EBML, 4)));
+    // Longer than 8 bytes, or cut off
+    assert_eq!(vint(&[0x00, 0x01], false), None);
+    assert_eq!(vint(&[0x40], false), None);
+    assert_eq!(vint(&[], false), None);
+  }
+
+  #[test]
+  fn unknown_sizes() {
+    assert_eq!(known(0x7F, 1), None);
+    assert_eq!(known(0x3FFF, 2), None);
+    assert_eq!(known(0x00FF_FFFF_FFFF_FFFF, 8), None);
+    assert_eq!(known(0x7E, 1), Some(0x7E));
+    assert_eq!(known(0x7F, 2), Some(0x7F));
+  }
+
+  #[test]
+  fn children_stop_at_truncated_elements() {
+    let data = [element(FILE_NAME, b"a.ttf"), vec![0x46, 0x60, 0x85, b'x']].concat();
+    assert_eq!(children(&data), vec![(FILE_NAME, b"a.ttf".as_slice())]);
+  }
+
+  #[test]
+  fn reads_attachments_behind_clusters() {
+    let stored = element(ATTACHMENTS, &[attached_file("a.ttf", "font/ttf", b"font a"), attached_file("b.otf", "", b"font b")].concat());
+    // 0x1F43B675 is a Cluster
+    let segment = [element(0x1F43B675, &[0; 64]), stored].concat();
+    // A Segment of unknown size reaches to the end of the file
+    let data = [element(EBML, &[]), vec![0x18, 0x53, 0x80, 0x67, 0xFF], segment].concat();
+    let path = write("attachments", &data);
+    let files = attachments(&path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!((files[0].filename.as_str(), files[0].mimetype.as_str(), files[0].data.as_slice()), ("a.ttf", "font/ttf", b"font a".as_slice()));
+    assert_eq!((files[1].filename.as_str(), files[1].mimetype.as_str(), files[1].data.as_slice()), ("b.otf", "", b"font b".as_slice()));
+  }
+
+  #[test]
+  fn oversized_attachments_are_rejected() {
+    let mut data = [element(EBML, &[]), element(SEGMENT, &element(ATTACHMENTS, &attached_file("a.ttf", "", b"font a")))].concat();
+    // Claim a terabyte
+    let size = data.len() - attached_file("a.ttf", "", b"font a").len() - 7;
+    data[size..size + 7].copy_from_slice(&[0, 0, 0xFF, 0, 0, 0, 0]);
+    let path = write("oversized", &data);
+    assert!(attachments(&path).is_err());
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn truncated_segments_are_rejected() {
+    // The Segment claims 2 bytes, but the Attachments header alone takes 10
+    let segment = element(ATTACHMENTS, &attached_file("a.ttf", "", b"font a"));
+    let data = [element(EBML, &[]), vec![0x18, 0x53, 0x80, 0x67, 0x82], segment].concat();
+    let path = write("truncated", &data);
+    assert!(attachments(&path).is_err());
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn other_files_are_rejected() {
+    let path = write("other", b"RIFF....AVI ");
+    assert!(attachments(&path).is_err());
+    fs::remove_file(path).unwrap();
+  }
+}
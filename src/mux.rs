@@ -0,0 +1,707 @@
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use encoding_rs::Encoding;
+use sha2::{Digest, Sha256};
+
+use crate::ass_deserialize::AssFile;
+use crate::decode::decode;
+use crate::matroska::AttachedFile;
+use crate::sfnt::{family_style, full_names};
+use crate::track::{iso_639_2, TrackOptions};
+
+/// A font file that goes into the container.
+pub struct Attachment {
+  pub path: PathBuf,
+  pub mimetype: Option<String>,
+  /// Name the attachment gets inside the container, unique within it
+  pub filename: OsString,
+  /// SHA-256 of the file as lowercase hex
+  pub hash: String,
+  /// Full names of the font, to recognize other versions of it
+  pub full_names: Vec<String>,
+  /// Font files this attachment is used for, including ones with the same content
+  pub sources: Vec<PathBuf>
+}
+
+/// How attachments are named inside the container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Naming {
+  /// File name of the font file
+  Original,
+  /// "Family-Style.ext" from the font's name table, so local file names don't end up in releases
+  Family,
+  /// SHA-256 of the file content
+  Hash
+}
+
+impl Naming {
+  pub const NAMES: [&'static str; 3] = ["original", "family", "hash"];
+
+  pub fn from_name(name: &str) -> Option<Naming> {
+    match name {
+      "original" => Some(Naming::Original),
+      "family" => Some(Naming::Family),
+      "hash" => Some(Naming::Hash),
+      _ => None
+    }
+  }
+
+  /// Name for the font file `path` with the content `data`. Fonts without a family in their name
+  /// table keep their file name.
+  fn filename(&self, path: &Path, data: &[u8], hash: &str) -> OsString {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    let name = match self {
+      Naming::Original => None,
+      Naming::Family => family_style(data).map(|(family, style)| format!("{}-{}", family, style)),
+      Naming::Hash => Some(hash.to_string())
+    };
+    match (name, extension) {
+      (Some(name), Some(extension)) => format!("{}.{}", sanitize(&name), extension).into(),
+      (Some(name), None) => sanitize(&name).into(),
+      (None, _) => path.file_name().unwrap_or_default().to_os_string()
+    }
+  }
+}
+
+/// What happens to fonts an output that is overwritten already has attached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+  /// Attached fonts win over newly found versions of them
+  Keep,
+  /// Newly found versions win, fonts the subtitles don't use stay
+  Replace,
+  /// Only what the subtitles use is attached
+  Prune
+}
+
+impl Existing {
+  pub const NAMES: [&'static str; 3] = ["keep", "replace", "prune"];
+
+  pub fn from_name(name: &str) -> Option<Existing> {
+    match name {
+      "keep" => Some(Existing::Keep),
+      "replace" => Some(Existing::Replace),
+      "prune" => Some(Existing::Prune),
+      _ => None
+    }
+  }
+}
+
+/// A subtitle file that goes into the container.
+pub struct Track<'a> {
+  pub input: &'a Path,
+  pub file: &'a AssFile,
+  pub options: TrackOptions
+}
+
+/// A solid color video track, so players start at 0:00 instead of at the first event.
+pub struct Placeholder {
+  /// Any color FFmpeg understands, e.g. "black" or "0x202020"
+  pub color: String,
+  /// PlayResX/Y of the first track, rounded up to even numbers for the encoder
+  pub size: (u32, u32),
+  /// Until the last event of all tracks ends, in centiseconds
+  pub duration: u64
+}
+
+/// Everything `run` is going to do for one container.
+pub struct MuxPlan {
+  pub output: PathBuf,
+  /// Subtitle files in stream order
+  pub inputs: Vec<(PathBuf, TrackOptions)>,
+  /// UTF-8 copies of the subtitle files in other encodings, which the muxer reads instead of them,
+  /// as (input, copy, encoding of the input)
+  pub converted: Vec<(PathBuf, PathBuf, String)>,
+  pub placeholder: Option<Placeholder>,
+  pub attachments: Vec<Attachment>,
+  /// Facenames without a font file
+  pub missing: Vec<String>,
+  /// Facenames whose file, or a file with the same content, is already attached for another facename
+  pub duplicates: Vec<(String, PathBuf)>,
+  /// What was done with the attachments of the output that is overwritten
+  pub changes: Vec<String>,
+  /// Kept attachments and converted subtitle files, written to these paths before muxing
+  pub extracted: Vec<(PathBuf, Vec<u8>)>,
+  /// Whether an existing output is overwritten
+  pub force: bool,
+  /// Muxer executable
+  pub program: &'static str,
+  /// Arguments the muxer is called with, passed on without going through a shell
+  pub args: Vec<OsString>
+}
+
+/// Plans one container with every track and the fonts of all of them. Files with the same content are
+/// attached once, sorted by path so the container doesn't depend on the order fonts are used in.
+/// `existing` are the attachments of the output that is overwritten, which are kept first. Muxers
+/// only read UTF-8, so subtitle files in other encodings are muxed from a converted copy.
+pub fn plan(
+  tracks: &[Track], output: &Path, force: bool, placeholder: Option<&str>, existing: (&[AttachedFile], Existing), naming: Naming,
+  muxer: &dyn Muxer
+) -> Result<MuxPlan, String> {
+  let mut attachments: Vec<Attachment> = vec![];
+  let mut missing: Vec<String> = vec![];
+  let mut duplicates: Vec<(String, PathBuf)> = vec![];
+  let mut seen: Vec<(&String, &PathBuf)> = vec![];
+
+  for assfont in tracks.iter().flat_map(|t| &t.file.fonts) {
+    let path = match &assfont.path {
+      Some(path) => path,
+      None => {
+        if ! missing.contains(&assfont.facename) {
+          missing.push(assfont.facename.clone());
+        }
+        continue;
+      }
+    };
+    // Tracks of one container often use the same fonts
+    if seen.contains(&(&assfont.facename, path)) {
+      continue;
+    }
+    seen.push((&assfont.facename, path));
+    if attachments.iter().any(|a| &a.path == path) {
+      duplicates.push((assfont.facename.clone(), path.clone()));
+      continue;
+    }
+
+    let data = match fs::read(path) {
+      Ok(data) => data,
+      Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err))
+    };
+    let hash = sha256(&data);
+    if let Some(attachment) = attachments.iter_mut().find(|a| a.hash == hash) {
+      duplicates.push((assfont.facename.clone(), attachment.path.clone()));
+      if ! attachment.sources.contains(path) {
+        attachment.sources.push(path.clone());
+      }
+      continue;
+    }
+
+    let filename = naming.filename(path, &data, &hash);
+    let full_names = full_names(&data);
+    attachments.push(Attachment { path: path.clone(), mimetype: mimetype(path).map(String::from), filename, hash, full_names, sources: vec![path.clone()] });
+  };
+
+  if attachments.is_empty() {
+    return Err("None of the required fonts could be found!".to_string());
+  }
+  attachments.sort_by(|a, b| a.path.cmp(&b.path));
+
+  let inputs = tracks.iter().map(|t| (t.input.to_path_buf(), t.options.clone())).collect();
+  let placeholder = placeholder.map(|color| {
+    let (width, height) = tracks[0].file.play_res;
+    Placeholder {
+      color: color.to_string(),
+      size: (width + width % 2, height + height % 2),
+      // At least one frame
+      duration: tracks.iter().map(|t| t.file.end).max().unwrap_or(0).max(4)
+    }
+  });
+  let mut plan = MuxPlan {
+    output: output.to_path_buf(),
+    inputs,
+    converted: vec![],
+    placeholder,
+    attachments: vec![],
+    changes: vec![],
+    extracted: vec![],
+    missing,
+    duplicates,
+    force,
+    program: muxer.program(),
+    args: vec![]
+  };
+  static PLANS: AtomicUsize = AtomicUsize::new(0);
+  let dir = std::env::temp_dir().join(format!("fa_tool-{}-{}", std::process::id(), PLANS.fetch_add(1, Ordering::Relaxed)));
+  for (index, track) in tracks.iter().enumerate().filter(|(_, t)| t.file.text_encoding != "UTF-8") {
+    let bytes = fs::read(track.input).map_err(|err| format!("Failed to read {}: {}", track.input.display(), err))?;
+    let (text, _) = decode(&bytes, Encoding::for_label(track.file.text_encoding.as_bytes()))
+      .map_err(|err| format!("Failed to convert {}: {}", track.input.display(), err))?;
+    let copy = dir.join(format!("track-{}", index)).join(track.input.file_name().unwrap_or_default());
+    plan.converted.push((track.input.to_path_buf(), copy.clone(), track.file.text_encoding.clone()));
+    plan.extracted.push((copy, text.into_bytes()));
+  }
+  let (existing, policy) = existing;
+  plan.attachments = merge_existing(existing, policy, &dir, &mut attachments, &mut plan);
+  plan.attachments.append(&mut attachments);
+  unique_filenames(&mut plan.attachments);
+  plan.args = muxer.args(&plan);
+  Ok(plan)
+}
+
+/// A program that writes the container.
+pub trait Muxer {
+  /// Name of the executable, looked up in PATH
+  fn program(&self) -> &'static str;
+
+  /// Arguments that write the planned container.
+  fn args(&self, plan: &MuxPlan) -> Vec<OsString>;
+
+  /// Whether the program can generate a `Placeholder` video.
+  fn generates_video(&self) -> bool {
+    false
+  }
+
+  /// Whether the program finished without an error, given its exit code.
+  fn succeeded(&self, code: Option<i32>) -> bool {
+    code == Some(0)
+  }
+}
+
+pub struct Ffmpeg;
+
+pub struct Mkvmerge;
+
+/// Names `--muxer` accepts.
+pub const MUXERS: [&str; 2] = ["ffmpeg", "mkvmerge"];
+
+pub fn muxer_from_name(name: &str) -> Option<&'static (dyn Muxer + Sync)> {
+  match name {
+    "ffmpeg" => Some(&Ffmpeg),
+    "mkvmerge" => Some(&Mkvmerge),
+    _ => None
+  }
+}
+
+impl Muxer for Ffmpeg {
+  fn program(&self) -> &'static str {
+    "ffmpeg"
+  }
+
+  fn generates_video(&self) -> bool {
+    true
+  }
+
+  fn args(&self, plan: &MuxPlan) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![];
+    if let Some(placeholder) = &plan.placeholder {
+      let (width, height) = placeholder.size;
+      let source = format!(
+        "color=c={}:s={}x{}:r=25:d={}.{:02}",
+        placeholder.color, width, height, placeholder.duration / 100, placeholder.duration % 100
+      );
+      args.append(&mut vec!["-f".into(), "lavfi".into(), "-i".into(), source.into()]);
+    }
+    for (input, _) in &plan.inputs {
+      args.push("-i".into());
+      args.push(plan.source(input).into());
+    }
+    // FFmpeg only picks one stream of each type by itself
+    let streams = plan.inputs.len() + plan.placeholder.is_some() as usize;
+    if streams > 1 {
+      for index in 0..streams {
+        args.push("-map".into());
+        args.push(index.to_string().into());
+      }
+    }
+    // The video and subtitle tracks come first, attachments follow
+    for (index, attachment) in plan.attachments.iter().enumerate() {
+      let metadata = format!("-metadata:s:{}", streams + index);
+      args.push("-attach".into());
+      args.push(attachment.path.clone().into());
+      if let Some(mimetype) = &attachment.mimetype {
+        args.push(metadata.clone().into());
+        args.push(format!("mimetype={}", mimetype).into());
+      }
+      let mut filename = OsString::from("filename=");
+      filename.push(&attachment.filename);
+      args.push(metadata.into());
+      args.push(filename);
+    }
+    for (index, (_, track)) in plan.inputs.iter().enumerate() {
+      // FFmpeg writes the tag as is, but the Language element only takes ISO 639-2 codes
+      if let Some(language) = track.language.as_deref().and_then(iso_639_2) {
+        args.push(format!("-metadata:s:s:{}", index).into());
+        args.push(format!("language={}", language).into());
+      }
+      if let Some(title) = &track.title {
+        args.push(format!("-metadata:s:s:{}", index).into());
+        args.push(format!("title={}", title).into());
+      }
+      let disposition = match (track.default, track.forced) {
+        (true, true) => Some("default+forced"),
+        (true, false) => Some("default"),
+        (false, true) => Some("forced"),
+        (false, false) => None
+      };
+      if let Some(disposition) = disposition {
+        args.push(format!("-disposition:s:{}", index).into());
+        args.push(disposition.into());
+      }
+    }
+    // The muxer isn't guessed from the extension, which FFmpeg doesn't know for .mks
+    args.append(&mut vec!["-f".into(), "matroska".into(), plan.output.clone().into()]);
+
+    if plan.force {
+      args.push("-y".into());
+    } else {
+      args.push("-n".into());
+    }
+    args
+  }
+}
+
+impl Muxer for Mkvmerge {
+  fn program(&self) -> &'static str {
+    "mkvmerge"
+  }
+
+  /// mkvmerge always overwrites, `run` has already skipped existing outputs unless forced.
+  fn args(&self, plan: &MuxPlan) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["-o".into(), plan.output.clone().into()];
+    // Options before a file apply to its tracks, and a subtitle file only has track 0
+    for (input, track) in &plan.inputs {
+      if let Some(language) = &track.language {
+        args.push("--language".into());
+        args.push(format!("0:{}", language).into());
+      }
+      if let Some(title) = &track.title {
+        args.push("--track-name".into());
+        args.push(format!("0:{}", title).into());
+      }
+      // mkvmerge makes every track default unless told otherwise
+      args.push("--default-track-flag".into());
+      args.push(if track.default { "0:yes" } else { "0:no" }.into());
+      if track.forced {
+        args.push("--forced-display-flag".into());
+        args.push("0:yes".into());
+      }
+      args.push(plan.source(input).into());
+    }
+    for attachment in &plan.attachments {
+      if let Some(mimetype) = &attachment.mimetype {
+        args.push("--attachment-mime-type".into());
+        args.push(mimetype.into());
+      }
+      args.push("--attachment-name".into());
+      args.push(attachment.filename.clone());
+      args.push("--attach-file".into());
+      args.push(attachment.path.clone().into());
+    }
+    args
+  }
+
+  /// Exit code 1 means there were only warnings.
+  fn succeeded(&self, code: Option<i32>) -> bool {
+    matches!(code, Some(0 | 1))
+  }
+}
+
+impl MuxPlan {
+  pub fn execute(&self, muxer: &dyn Muxer) -> Result<(), String> {
+    for (path, data) in &self.extracted {
+      let written = fs::create_dir_all(path.parent().unwrap()).and_then(|_| fs::write(path, data));
+      if let Err(err) = written {
+        return Err(format!("Failed to write {}: {}", path.display(), err));
+      }
+    }
+    let result = std::process::Command::new(self.program).args(&self.args).output();
+    // The written files are in a folder of their own, two levels up
+    if let Some(dir) = self.extracted.first().and_then(|(path, _)| path.parent()?.parent()) {
+      let _ = fs::remove_dir_all(dir);
+    }
+    let result = match result {
+      Ok(result) => result,
+      Err(err) => return Err(format!("Failed to run {}: {}", self.program, err))
+    };
+    match muxer.succeeded(result.status.code()) {
+      true => Ok(()),
+      false => {
+        // mkvmerge reports its errors on stdout
+        match result.stderr.is_empty() {
+          true => Err(String::from_utf8_lossy(&result.stdout).to_string()),
+          false => Err(String::from_utf8_lossy(&result.stderr).to_string())
+        }
+      }
+    }
+  }
+
+  /// The file the muxer reads for the subtitle file `input`, which is a UTF-8 copy if it had to be
+  /// converted.
+  pub fn source<'a>(&'a self, input: &'a Path) -> &'a Path {
+    self.converted.iter().find(|(path, _, _)| path == input).map_or(input, |(_, copy, _)| copy)
+  }
+
+  /// Name in the container of the attachment used for the font file `path`.
+  pub fn attachment_name(&self, path: &Path) -> Option<&OsString> {
+    self.attachments.iter().find(|a| a.sources.iter().any(|source| source == path)).map(|a| &a.filename)
+  }
+
+  /// The muxer call as it could be pasted into a shell. The temporary files only exist while muxing,
+  /// so converted subtitle files are shown as the original and extracted attachments by their file
+  /// name, which `describe` explains. Arguments that aren't valid UTF-8 can't be shown exactly.
+  pub fn command_line(&self) -> String {
+    let mut line = self.program.to_string();
+    for arg in &self.args {
+      let original = self.converted.iter().find(|(_, copy, _)| copy.as_os_str() == arg).map(|(input, _, _)| input.as_os_str());
+      let extracted = self.extracted.iter().find(|(path, _)| path.as_os_str() == arg).and_then(|(path, _)| path.file_name());
+      let arg = original.or(extracted).unwrap_or(arg);
+      line.push(' ');
+      line.push_str(&shell_quote(&arg.to_string_lossy()));
+    }
+    line
+  }
+
+  /// Lists what would happen, for `--dry-run`.
+  pub fn describe(&self, log: &mut String) {
+    writeln!(log, "  output: {}", self.output.display()).unwrap();
+    if let Some(placeholder) = &self.placeholder {
+      let (width, height) = placeholder.size;
+      let seconds = placeholder.duration / 100;
+      writeln!(
+        log, "  video:  {} {}x{} for {}:{:02}:{:02}.{:02}",
+        placeholder.color, width, height, seconds / 3600, seconds / 60 % 60, seconds % 60, placeholder.duration % 100
+      ).unwrap();
+    }
+    for (input, options) in &self.inputs {
+      let mut track: Vec<String> = vec![];
+      if let Some(language) = &options.language {
+        track.push(format!("language {}", language));
+      }
+      if let Some(title) = &options.title {
+        track.push(format!("title \"{}\"", title));
+      }
+      if options.default {
+        track.push("default".to_string());
+      }
+      if options.forced {
+        track.push("forced".to_string());
+      }
+      if let Some((_, _, encoding)) = self.converted.iter().find(|(path, _, _)| path == input) {
+        track.push(format!("converted from {} to UTF-8", encoding));
+      }
+      if track.is_empty() {
+        writeln!(log, "  track:  {}", input.display()).unwrap();
+      } else {
+        writeln!(log, "  track:  {}  ({})", input.display(), track.join(", ")).unwrap();
+      }
+    }
+    for attachment in &self.attachments {
+      let source = match self.extracted.iter().any(|(path, _)| *path == attachment.path) {
+        true => format!("{} extracted from the existing output", attachment.path.file_name().unwrap_or_default().to_string_lossy()),
+        false => attachment.path.display().to_string()
+      };
+      writeln!(log, "  attach: {}  ({}, {})", source, attachment.mimetype.as_deref().unwrap_or("no mimetype"), attachment.filename.to_string_lossy()).unwrap();
+    }
+    for facename in &self.missing {
+      writeln!(log, "  skip:   \"{}\" (not found)", facename).unwrap();
+    }
+    for (facename, path) in &self.duplicates {
+      writeln!(log, "  skip:   \"{}\" (already attached as {})", facename, path.display()).unwrap();
+    }
+    for change in &self.changes {
+      writeln!(log, "  existing: {}", change).unwrap();
+    }
+    writeln!(log, "  {}", self.command_line()).unwrap();
+  }
+}
+
+/// Decides which attachments of an overwritten output stay, removing newly found fonts from
+/// `attachments` if an attached version of them is kept. Returns the kept attachments and fills in
+/// what happened to each one and which files have to be written for them below `dir`, see `MuxPlan`.
+fn merge_existing(
+  existing: &[AttachedFile], policy: Existing, dir: &Path, attachments: &mut Vec<Attachment>, plan: &mut MuxPlan
+) -> Vec<Attachment> {
+  let mut kept: Vec<Attachment> = vec![];
+
+  for (index, file) in existing.iter().enumerate() {
+    let hash = sha256(&file.data);
+    let mut sources: Vec<PathBuf> = vec![];
+    if attachments.iter().any(|a| a.hash == hash) {
+      plan.changes.push(format!("\"{}\" is unchanged.", file.filename));
+      continue;
+    }
+    let names = full_names(&file.data);
+    let same_font = attachments.iter()
+      .position(|a| a.full_names.iter().any(|name| names.iter().any(|n| n.eq_ignore_ascii_case(name))));
+    match (same_font, policy) {
+      (Some(position), Existing::Keep) => {
+        let attachment = attachments.remove(position);
+        plan.changes.push(format!("Kept \"{}\" instead of {}.", file.filename, attachment.path.display()));
+        sources = attachment.sources;
+      },
+      (Some(position), _) => {
+        plan.changes.push(format!("Replaced \"{}\" with {}.", file.filename, attachments[position].path.display()));
+        continue;
+      },
+      (None, Existing::Prune) => {
+        plan.changes.push(format!("Removed \"{}\", which the subtitles don't use.", file.filename));
+        continue;
+      },
+      (None, _) => plan.changes.push(format!("Kept \"{}\", which the subtitles don't use.", file.filename))
+    }
+    // Attachment names can be anything, only their last component is used as a file name
+    let name = Path::new(&file.filename).file_name().map(|n| n.to_os_string()).unwrap_or("attachment".into());
+    let path = dir.join(index.to_string()).join(&name);
+    // The stored mimetype is kept, the extension only helps if there is none
+    let mimetype = Some(file.mimetype.clone()).filter(|m| ! m.is_empty()).or_else(|| mimetype(&path).map(String::from));
+    kept.push(Attachment { path: path.clone(), mimetype, filename: file.filename.clone().into(), hash, full_names: names, sources });
+    plan.extracted.push((path, file.data.clone()));
+  }
+  kept
+}
+
+fn sha256(data: &[u8]) -> String {
+  Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn mimetype(path: &Path) -> Option<&'static str> {
+  let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+  match extension.as_deref() {
+    Some("ttf") => Some("application/x-truetype-font"),
+    Some("otf") => Some("application/x-font-opentype"),
+    Some("ttc") => Some("application/x-truetype-collection"),
+    _ => None
+  }
+}
+
+/// Replaces characters that aren't allowed in file names on Windows.
+fn sanitize(name: &str) -> String {
+  name.chars().map(|c| if c.is_control() || "/\\:*?\"<>|".contains(c) { '_' } else { c }).collect()
+}
+
+/// Numbers attachment names that are already taken, e.g. a second Regular.ttf becomes Regular-2.ttf.
+/// Names are compared case-insensitively since players look them up that way on some systems.
+fn unique_filenames(attachments: &mut [Attachment]) {
+  let mut taken: Vec<String> = vec![];
+  for attachment in attachments {
+    let path = Path::new(&attachment.filename);
+    let stem = path.file_stem().unwrap_or_default().to_os_string();
+    let extension = path.extension().map(|e| e.to_os_string());
+    let mut number = 1;
+    loop {
+      let mut filename = stem.clone();
+      if number > 1 {
+        filename.push(format!("-{}", number));
+      }
+      if let Some(extension) = &extension {
+        filename.push(".");
+        filename.push(extension);
+      }
+      let key = filename.to_string_lossy().to_lowercase();
+      if ! taken.contains(&key) {
+        taken.push(key);
+        attachment.filename = filename;
+        break;
+      }
+      number += 1;
+    }
+  }
+}
+
+/// Quotes an argument for sh, or for cmd.exe on Windows, if it needs it.
+pub fn shell_quote(arg: &str) -> String {
+  let plain = ! arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+  if plain {
+    arg.to_string()
+  } else if cfg!(windows) {
+    format!("\"{}\"", arg.replace('"', "\\\""))
+  } else {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ass_deserialize::AssFont;
+
+  use super::*;
+
+  fn fixture_font() -> Vec<u8> {
+    fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("FixtureSans-Regular.ttf")).unwrap()
+  }
+
+  /// A fresh folder with the given files.
+  fn folder(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fa_tool-mux-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, data) in files {
+      fs::write(dir.join(file), data).unwrap();
+    }
+    dir
+  }
+
+  /// Plans a container for a UTF-8 script whose fonts were matched to files in `dir`, given as
+  /// (facename, file name).
+  fn plan_for(dir: &Path, fonts: &[(&str, &str)], existing: &[AttachedFile], policy: Existing) -> MuxPlan {
+    let fonts = fonts.iter()
+      .map(|(facename, file)| AssFont { path: Some(dir.join(file)), ..AssFont::new(facename.to_string(), false, false, 1) })
+      .collect();
+    let file = AssFile { fonts, text_encoding: "UTF-8".to_string(), warnings: vec![], undefined_styles: vec![], play_res: (384, 288), end: 100 };
+    let input = dir.join("ep01.ass");
+    let tracks = [Track { input: &input, file: &file, options: TrackOptions::default() }];
+    plan(&tracks, &dir.join("ep01.mkv"), true, None, (existing, policy), Naming::Original, &Ffmpeg).unwrap()
+  }
+
+  fn filenames(plan: &MuxPlan) -> Vec<String> {
+    plan.attachments.iter().map(|a| a.filename.to_string_lossy().to_string()).collect()
+  }
+
+  #[test]
+  fn names_are_unique_ignoring_case() {
+    let dir = folder("case", &[("a.ttf", b"font a"), ("A.TTF", b"font b"), ("a-2.ttf", b"font c")]);
+    let plan = plan_for(&dir, &[("A", "a.ttf"), ("B", "A.TTF"), ("C", "a-2.ttf")], &[], Existing::Replace);
+    fs::remove_dir_all(&dir).unwrap();
+    // Attachments are sorted by path: A.TTF, a-2.ttf, a.ttf
+    assert_eq!(filenames(&plan), vec!["A.TTF", "a-2.ttf", "a-3.ttf"]);
+  }
+
+  #[test]
+  fn files_with_the_same_content_are_attached_once() {
+    let dir = folder("dedupe", &[("a.ttf", b"font a"), ("copy.ttf", b"font a")]);
+    let plan = plan_for(&dir, &[("A", "a.ttf"), ("A", "a.ttf"), ("Copy", "copy.ttf")], &[], Existing::Replace);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(filenames(&plan), vec!["a.ttf"]);
+    assert_eq!(plan.attachments[0].sources, vec![dir.join("a.ttf"), dir.join("copy.ttf")]);
+    assert_eq!(plan.duplicates, vec![("Copy".to_string(), dir.join("a.ttf"))]);
+  }
+
+  #[test]
+  fn unused_attachments_are_kept_unless_pruned() {
+    let dir = folder("unused", &[("a.ttf", b"font a")]);
+    let existing = [AttachedFile { filename: "cover.jpg".to_string(), mimetype: "image/jpeg".to_string(), data: b"jpeg".to_vec() }];
+    let plan = plan_for(&dir, &[("A", "a.ttf")], &existing, Existing::Replace);
+    assert_eq!(filenames(&plan), vec!["cover.jpg", "a.ttf"]);
+    assert_eq!(plan.attachments[0].mimetype.as_deref(), Some("image/jpeg"));
+    assert_eq!(plan.extracted, vec![(plan.attachments[0].path.clone(), b"jpeg".to_vec())]);
+    assert_eq!(plan.changes, vec!["Kept \"cover.jpg\", which the subtitles don't use."]);
+    let plan = plan_for(&dir, &[("A", "a.ttf")], &existing, Existing::Prune);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(filenames(&plan), vec!["a.ttf"]);
+    assert_eq!(plan.changes, vec!["Removed \"cover.jpg\", which the subtitles don't use."]);
+  }
+
+  #[test]
+  fn other_versions_of_attached_fonts() {
+    let font = fixture_font();
+    let dir = folder("versions", &[("FixtureSans-Regular.ttf", &font)]);
+    let path = dir.join("FixtureSans-Regular.ttf");
+    let fonts = [("Fixture Sans", "FixtureSans-Regular.ttf")];
+    // Another version has the same full name but other bytes
+    let attached = |data: Vec<u8>| [AttachedFile { filename: "FixtureSans-Regular.ttf".to_string(), mimetype: "font/ttf".to_string(), data }];
+    let old = attached([font.as_slice(), &[0; 4]].concat());
+
+    let plan = plan_for(&dir, &fonts, &old, Existing::Replace);
+    assert_eq!(filenames(&plan), vec!["FixtureSans-Regular.ttf"]);
+    assert_eq!(plan.attachments[0].path, path);
+    assert_eq!(plan.changes, vec![format!("Replaced \"FixtureSans-Regular.ttf\" with {}.", path.display())]);
+
+    let plan = plan_for(&dir, &fonts, &old, Existing::Keep);
+    assert_eq!(filenames(&plan), vec!["FixtureSans-Regular.ttf"]);
+    assert_eq!(plan.extracted.len(), 1);
+    assert_eq!(plan.attachments[0].path, plan.extracted[0].0);
+    assert_eq!(plan.attachments[0].mimetype.as_deref(), Some("font/ttf"));
+    assert_eq!(plan.attachments[0].sources, vec![path.clone()]);
+    assert_eq!(plan.changes, vec![format!("Kept \"FixtureSans-Regular.ttf\" instead of {}.", path.display())]);
+
+    let plan = plan_for(&dir, &fonts, &attached(font.clone()), Existing::Keep);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(filenames(&plan), vec!["FixtureSans-Regular.ttf"]);
+    assert_eq!(plan.attachments[0].path, path);
+    assert_eq!(plan.changes, vec!["\"FixtureSans-Regular.ttf\" is unchanged."]);
+  }
+}
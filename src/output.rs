@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use clap::{Arg, ArgAction, ArgMatches, value_parser};
+
+/// Default naming template, which appends the container extension to the subtitle file name.
+pub const DEFAULT_TEMPLATE: &str = "{filename}.{ext}";
+
+/// Where `run` writes its containers and how it names them.
+pub struct OutputOptions {
+  /// Next to the subtitle file if not set
+  pub dir: Option<PathBuf>,
+  pub template: String,
+  /// "mkv" or "mks"
+  pub extension: String
+}
+
+pub fn output_args() -> [Arg; 3] {
+  [
+    Arg::new("output-dir")
+    .short('o')
+    .long("output-dir")
+    .help("Folder to write the containers to instead of next to each subtitle file.")
+    .required(false)
+    .action(ArgAction::Set)
+    .value_parser(value_parser!(PathBuf)),
+    Arg::new("name")
+    .long("name")
+    .help("Naming template for the containers, e.g. \"{stem}.fonts.{ext}\". {filename} is the subtitle file name, {stem} the same without extension and {ext} the container extension.")
+    .required(false)
+    .action(ArgAction::Set)
+    .default_value(DEFAULT_TEMPLATE),
+    Arg::new("container")
+    .long("container")
+    .help("Container extension: \"mkv\" (default) or \"mks\" for a subtitle-only Matroska file.")
+    .required(false)
+    .action(ArgAction::Set)
+    .value_parser(["mkv", "mks"])
+    .default_value("mkv")
+  ]
+}
+
+impl OutputOptions {
+  pub fn from_matches(matches: &ArgMatches) -> OutputOptions {
+    let template = matches.get_one::<String>("name").unwrap().to_string();
+    if ! valid_template(&template) {
+      println!("Invalid naming template \"{}\", only {{filename}}, {{stem}} and {{ext}} can be used!", template);
+      exit(1);
+    }
+    OutputOptions {
+      dir: matches.get_one::<PathBuf>("output-dir").cloned(),
+      template,
+      extension: matches.get_one::<String>("container").unwrap().to_string()
+    }
+  }
+
+  /// Path of the container for the subtitle file `path`. File names don't have to be valid UTF-8.
+  pub fn output_path(&self, path: &Path) -> PathBuf {
+    let mut name = OsString::new();
+    let mut rest = self.template.as_str();
+    // from_matches made sure the template is valid
+    while let Some(open) = rest.find('{') {
+      let close = open + rest[open..].find('}').unwrap();
+      name.push(&rest[..open]);
+      match &rest[open + 1..close] {
+        "filename" => name.push(path.file_name().unwrap_or_default()),
+        "stem" => name.push(path.file_stem().unwrap_or_default()),
+        _ => name.push(&self.extension)
+      }
+      rest = &rest[close + 1..];
+    }
+    name.push(rest);
+    let dir = match &self.dir {
+      Some(dir) => dir.as_path(),
+      None => path.parent().unwrap_or(Path::new(""))
+    };
+    dir.join(name)
+  }
+
+  /// Outputs that more than one input would be written to, with those inputs.
+  pub fn collisions(&self, inputs: &[PathBuf]) -> Vec<(PathBuf, Vec<PathBuf>)> {
+    let mut outputs: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for input in inputs {
+      outputs.entry(self.output_path(input)).or_default().push(input.clone());
+    }
+    outputs.into_iter().filter(|(_, inputs)| inputs.len() > 1).collect()
+  }
+}
+
+/// Whether every `{` of a naming template starts a known placeholder that is closed.
+fn valid_template(template: &str) -> bool {
+  let mut rest = template;
+  while let Some(open) = rest.find('{') {
+    let close = rest[open..].find('}').map(|c| open + c);
+    match close.map(|close| &rest[open + 1..close]) {
+      Some("filename" | "stem" | "ext") => rest = &rest[close.unwrap() + 1..],
+      _ => return false
+    }
+  }
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn options(template: &str, dir: Option<&str>, extension: &str) -> OutputOptions {
+    OutputOptions { dir: dir.map(PathBuf::from), template: template.to_string(), extension: extension.to_string() }
+  }
+
+  #[test]
+  fn templates_only_know_three_placeholders() {
+    assert!(valid_template(DEFAULT_TEMPLATE));
+    assert!(valid_template("{stem}.fonts.{ext}"));
+    assert!(valid_template("plain"));
+    assert!(valid_template("}{ext}"));
+    assert!(! valid_template("{stem"));
+    assert!(! valid_template("{name}.{ext}"));
+    assert!(! valid_template("{{stem}}"));
+  }
+
+  #[test]
+  fn outputs_go_next_to_the_input_or_into_the_output_dir() {
+    let path = Path::new("subs").join("ep01.en.ass");
+    assert_eq!(options(DEFAULT_TEMPLATE, None, "mkv").output_path(&path), Path::new("subs").join("ep01.en.ass.mkv"));
+    assert_eq!(options("{stem}.fonts.{ext}", Some("out"), "mks").output_path(&path), Path::new("out").join("ep01.en.fonts.mks"));
+    assert_eq!(options("{filename}", None, "mkv").output_path(Path::new("ep01.ass")), Path::new("ep01.ass"));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn file_names_dont_have_to_be_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let path = Path::new(OsStr::from_bytes(b"ep\xff.ass"));
+    assert_eq!(options("{stem}.{ext}", None, "mkv").output_path(path).as_os_str().as_bytes(), b"ep\xff.mkv");
+  }
+
+  #[test]
+  fn collisions_list_every_input() {
+    let inputs = [PathBuf::from("a/ep01.ass"), PathBuf::from("a/ep01.ssa"), PathBuf::from("b/ep01.ass"), PathBuf::from("a/ep02.ass")];
+    let collisions = options("{stem}.{ext}", None, "mkv").collisions(&inputs);
+    assert_eq!(collisions, vec![(PathBuf::from("a/ep01.mkv"), vec![inputs[0].clone(), inputs[1].clone()])]);
+    let collisions = options("{stem}.{ext}", Some("out"), "mkv").collisions(&inputs);
+    assert_eq!(collisions, vec![(PathBuf::from("out/ep01.mkv"), inputs[..3].to_vec())]);
+    assert!(options(DEFAULT_TEMPLATE, Some("out"), "mkv").collisions(&inputs[1..]).is_empty());
+  }
+}
@@ -0,0 +1,209 @@
+/// Tags whose argument is wrapped in parentheses and may itself contain backslashes or commas.
+const PARENTHESIZED: [&str; 8] = ["t", "clip", "iclip", "pos", "org", "move", "fad", "fade"];
+
+/// Tag names libass knows. The longest one that matches wins, so `\fscx` isn't read as `\fs` + "cx".
+const NAMES: [&str; 53] = [
+  "xshad", "yshad", "xbord", "ybord", "iclip", "alpha",
+  "clip", "bord", "blur", "fscx", "fscy", "fade", "move", "shad",
+  "fax", "fay", "frx", "fry", "frz", "fsp", "pos", "org", "pbo", "fad",
+  "fn", "fs", "fe", "fr", "an", "be", "kf", "ko", "kt", "1c", "2c", "3c", "4c", "1a", "2a", "3a", "4a",
+  "b", "i", "u", "s", "c", "a", "k", "K", "q", "p", "r", "t"
+];
+
+/// One tag of an override block, e.g. `fn` with the argument "Arial" for `\fnArial`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverrideTag<'a> {
+  pub name: &'a str,
+  /// Everything after the name, without the parentheses of tags like `\clip(...)`
+  pub argument: &'a str,
+  /// 0-based character column of the backslash in the event text
+  pub column: usize,
+  /// Whether the tag is part of a `\t` transform
+  pub in_transform: bool
+}
+
+impl OverrideTag<'_> {
+  /// 0-based character column where the argument starts.
+  pub fn argument_column(&self) -> usize {
+    self.column + 1 + self.name.chars().count()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment<'a> {
+  /// The tags of one `{...}` block. Tags of a `\t` follow the `\t` itself.
+  Tags(Vec<OverrideTag<'a>>),
+  /// Text between blocks, with `\{` and `\}` already turned into plain braces
+  Text(String)
+}
+
+impl Segment<'_> {
+  /// Whether this is text that produces glyphs, as opposed to line breaks and spaces.
+  pub fn is_visible_text(&self) -> bool {
+    match self {
+      Segment::Text(text) => ! text.replace("\\N", "").replace("\\n", "").replace("\\h", "").trim().is_empty(),
+      Segment::Tags(_) => false
+    }
+  }
+}
+
+pub struct EventText<'a> {
+  pub segments: Vec<Segment<'a>>,
+  /// 0-based character column of a `{` that is never closed. Everything after it is read as tags.
+  pub unclosed: Option<usize>
+}
+
+/// Splits the Text field of an event into override blocks and plain text.
+pub fn tokenize(text: &str) -> EventText<'_> {
+  let mut segments: Vec<Segment> = vec![];
+  let mut plain = String::new();
+  let mut unclosed: Option<usize> = None;
+  let mut byte = 0;
+  while let Some(ch) = text[byte..].chars().next() {
+    if ch == '\\' && (text[byte + 1..].starts_with('{') || text[byte + 1..].starts_with('}')) {
+      plain.push_str(&text[byte + 1..byte + 2]);
+      byte += 2;
+      continue;
+    }
+    if ch != '{' {
+      plain.push(ch);
+      byte += ch.len_utf8();
+      continue;
+    }
+    if ! plain.is_empty() {
+      segments.push(Segment::Text(std::mem::take(&mut plain)));
+    }
+    let column = text[..byte].chars().count();
+    let (block, next) = match text[byte + 1..].find('}') {
+      Some(end) => (&text[byte + 1..byte + 1 + end], byte + end + 2),
+      None => {
+        unclosed = Some(column);
+        (&text[byte + 1..], text.len())
+      }
+    };
+    let mut tags: Vec<OverrideTag> = vec![];
+    parse_block(block, column + 1, false, &mut tags);
+    segments.push(Segment::Tags(tags));
+    byte = next;
+  }
+  if ! plain.is_empty() {
+    segments.push(Segment::Text(plain));
+  }
+  EventText { segments, unclosed }
+}
+
+/// Reads the tags of a block that starts at the character `column`. Anything before the first
+/// backslash, like comments, is skipped.
+fn parse_block<'a>(block: &'a str, column: usize, in_transform: bool, tags: &mut Vec<OverrideTag<'a>>) {
+  let mut position = 0;
+  while let Some(backslash) = block[position..].find('\\').map(|b| position + b) {
+    let tag_column = column + block[..backslash].chars().count();
+    let rest = &block[backslash + 1..];
+    let name = NAMES.iter().filter(|n| rest.starts_with(*n)).max_by_key(|n| n.len()).map(|n| &rest[..n.len()])
+      .unwrap_or_else(|| &rest[..rest.find(|c: char| ! c.is_ascii_alphabetic()).unwrap_or(rest.len())]);
+    let after_name = &rest[name.len()..];
+
+    let open = after_name.len() - after_name.trim_start().len();
+    if PARENTHESIZED.contains(&name) && after_name[open..].starts_with('(') {
+      let inner = &after_name[open + 1..];
+      let close = closing_paren(inner);
+      let argument = &inner[..close];
+      let tag = OverrideTag { name, argument, column: tag_column, in_transform };
+      tags.push(tag);
+      if name == "t" {
+        if let Some(first) = argument.find('\\') {
+          let inner_column = tag_column + 1 + rest[..name.len() + open + 1].chars().count() + argument[..first].chars().count();
+          parse_block(&argument[first..], inner_column, true, tags);
+        }
+      }
+      position = backslash + 1 + name.len() + open + 1 + (close + 1).min(inner.len());
+    } else {
+      let end = after_name.find('\\').unwrap_or(after_name.len());
+      tags.push(OverrideTag { name, argument: &after_name[..end], column: tag_column, in_transform });
+      position = backslash + 1 + name.len() + end;
+    }
+  }
+}
+
+/// Byte offset of the parenthesis that closes an argument list, or its length if it's never closed.
+fn closing_paren(argument: &str) -> usize {
+  let mut depth = 0;
+  for (byte, ch) in argument.char_indices() {
+    match ch {
+      '(' => depth += 1,
+      ')' if depth == 0 => return byte,
+      ')' => depth -= 1,
+      _ => {}
+    }
+  }
+  argument.len()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tags<'a>(text: &EventText<'a>) -> Vec<OverrideTag<'a>> {
+    text.segments.iter().flat_map(|s| match s {
+      Segment::Tags(tags) => tags.clone(),
+      Segment::Text(_) => vec![]
+    }).collect()
+  }
+
+  fn tag(name: &'static str, argument: &'static str, column: usize, in_transform: bool) -> OverrideTag<'static> {
+    OverrideTag { name, argument, column, in_transform }
+  }
+
+  #[test]
+  fn escaped_braces_are_text() {
+    let text = tokenize(r"a\{b\}c{\b1}d");
+    assert_eq!(text.segments[0], Segment::Text("a{b}c".to_string()));
+    assert_eq!(tags(&text), vec![tag("b", "1", 8, false)]);
+    assert_eq!(text.segments[2], Segment::Text("d".to_string()));
+    assert_eq!(text.unclosed, None);
+  }
+
+  #[test]
+  fn longest_name_wins() {
+    let text = tokenize(r"{\fscx120\fs20\fnArial Bold}");
+    assert_eq!(tags(&text), vec![tag("fscx", "120", 1, false), tag("fs", "20", 9, false), tag("fn", "Arial Bold", 14, false)]);
+  }
+
+  #[test]
+  fn comments_before_tags_are_skipped() {
+    let text = tokenize(r"{note\i1}x");
+    assert_eq!(tags(&text), vec![tag("i", "1", 5, false)]);
+  }
+
+  #[test]
+  fn transforms_hold_nested_tags() {
+    let text = tokenize(r"{\t(0,100,\fs20\clip(1,2,3,4))\b1}");
+    assert_eq!(tags(&text), vec![
+      tag("t", r"0,100,\fs20\clip(1,2,3,4)", 1, false),
+      tag("fs", "20", 10, true),
+      tag("clip", "1,2,3,4", 15, true),
+      tag("b", "1", 30, false)
+    ]);
+  }
+
+  #[test]
+  fn unterminated_parentheses_take_the_rest_of_the_block() {
+    let text = tokenize(r"{\clip(1,2\fnArial}x");
+    assert_eq!(tags(&text), vec![tag("clip", r"1,2\fnArial", 1, false)]);
+    assert_eq!(text.segments[1], Segment::Text("x".to_string()));
+  }
+
+  #[test]
+  fn unclosed_blocks_are_read_as_tags() {
+    let text = tokenize(r"ab{\fnArial");
+    assert_eq!(text.unclosed, Some(2));
+    assert_eq!(tags(&text), vec![tag("fn", "Arial", 3, false)]);
+  }
+
+  #[test]
+  fn columns_count_characters() {
+    let text = tokenize(r"日本{\fnMS Gothic}");
+    assert_eq!(tags(&text), vec![tag("fn", "MS Gothic", 3, false)]);
+    assert_eq!(tags(&text)[0].argument_column(), 6);
+  }
+}
@@ -0,0 +1,87 @@
+use serde::Serialize;
+
+use crate::ass_deserialize::{AssFile, AssFont, MatchQuality, StyleReference};
+use crate::error::Warning;
+
+/// Outcome of processing one input file.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileRecord {
+  #[serde(rename = "file")]
+  pub name: String,
+  pub parsed: bool,
+  /// Encoding the file was stored in
+  pub encoding: Option<String>,
+  /// Fonts for which a file was found
+  pub resolved: usize,
+  pub missing: usize,
+  pub muxed: bool,
+  pub error: Option<String>,
+  pub fonts: Vec<AssFont>,
+  pub undefined_styles: Vec<StyleReference>,
+  pub warnings: Vec<Warning>
+}
+
+impl FileRecord {
+  pub fn new(name: &str) -> FileRecord {
+    FileRecord {
+      name: name.to_string(),
+      ..Default::default()
+    }
+  }
+
+  /// Takes over the fonts and parser findings of a file after `find_font_files`.
+  pub fn set_file(&mut self, file: &AssFile) {
+    self.parsed = true;
+    self.encoding = Some(file.text_encoding.clone());
+    self.missing = file.fonts.iter().filter(|f| f.quality == MatchQuality::Unresolved).count();
+    self.resolved = file.fonts.len() - self.missing;
+    self.fonts = file.fonts.clone();
+    self.undefined_styles = file.undefined_styles.clone();
+    self.warnings = file.warnings.clone();
+  }
+
+  fn status(&self, muxing: bool) -> &'static str {
+    if self.error.is_some() {
+      "failed"
+    } else if ! muxing || self.muxed {
+      "ok"
+    } else {
+      "skipped"
+    }
+  }
+}
+
+/// Prints one row per file and the totals. Without `muxing` the muxed column is left out.
+pub fn print_summary(records: &[FileRecord], muxing: bool) {
+  let width = records.iter().map(|r| r.name.chars().count()).max().unwrap_or(0).max(4);
+  println!("Summary:");
+  if muxing {
+    println!("  {:width$}  {:>6}  {:>8}  {:>7}  {:>5}  status", "file", "parsed", "resolved", "missing", "muxed");
+  } else {
+    println!("  {:width$}  {:>6}  {:>8}  {:>7}  status", "file", "parsed", "resolved", "missing");
+  }
+  for record in records {
+    let parsed = if record.parsed { "yes" } else { "no" };
+    if muxing {
+      let muxed = if record.muxed { "yes" } else { "no" };
+      println!("  {:width$}  {:>6}  {:>8}  {:>7}  {:>5}  {}", record.name, parsed, record.resolved, record.missing, muxed, record.status(muxing));
+    } else {
+      println!("  {:width$}  {:>6}  {:>8}  {:>7}  {}", record.name, parsed, record.resolved, record.missing, record.status(muxing));
+    }
+  }
+
+  let parsed = records.iter().filter(|r| r.parsed).count();
+  let resolved: usize = records.iter().map(|r| r.resolved).sum();
+  let missing: usize = records.iter().map(|r| r.missing).sum();
+  let failed = records.iter().filter(|r| r.error.is_some()).count();
+  if muxing {
+    let muxed = records.iter().filter(|r| r.muxed).count();
+    println!("{} files: {} parsed, {} fonts resolved, {} missing, {} muxed, {} failed.", records.len(), parsed, resolved, missing, muxed, failed);
+  } else {
+    println!("{} files: {} parsed, {} fonts resolved, {} missing, {} failed.", records.len(), parsed, resolved, missing, failed);
+  }
+}
+
+pub fn print_json<T: Serialize>(records: &[T]) {
+  println!("{}", serde_json::to_string_pretty(records).unwrap());
+}
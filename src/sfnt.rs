@@ -0,0 +1,130 @@
+use std::fs;
+use std::path::Path;
+use serde::Serialize;
+
+/// What a font offers for vertical (`@`) text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct VerticalSupport {
+  /// Has `vhea` and `vmtx` tables, so glyphs can be advanced top to bottom
+  pub metrics: bool,
+  /// Has a `vert` or `vrt2` GSUB feature with upright forms of punctuation and brackets
+  pub substitution: bool
+}
+
+impl VerticalSupport {
+  /// What libass' vertical layout would have to do without, if anything.
+  pub fn missing(&self) -> Option<&'static str> {
+    match (self.metrics, self.substitution) {
+      (true, true) => None,
+      (true, false) => Some("vert substitutions, so punctuation and brackets stay sideways"),
+      (false, true) => Some("vertical metrics, so glyph advances are made up from horizontal ones"),
+      (false, false) => Some("vertical metrics or vert substitutions")
+    }
+  }
+}
+
+/// Reads the OpenType tables of a font file. Collections are judged by their first face.
+pub fn vertical_support(path: &Path) -> Result<VerticalSupport, String> {
+  let data = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+  let invalid = || format!("{}: not an OpenType font", path.display());
+  let face = if data.starts_with(b"ttcf") {
+    read_u32(&data, 12).ok_or_else(invalid)? as usize
+  } else {
+    0
+  };
+  let tables = table_directory(&data, face).ok_or_else(invalid)?;
+  let find = |tag: &[u8; 4]| tables.iter().find(|(t, _, _)| t == tag).map(|(_, offset, length)| (*offset, *length));
+
+  let metrics = find(b"vhea").is_some() && find(b"vmtx").is_some();
+  let substitution = find(b"GSUB")
+    .and_then(|(offset, length)| data.get(offset..offset + length))
+    .and_then(feature_tags)
+    .is_some_and(|tags| tags.iter().any(|t| t == b"vert" || t == b"vrt2"));
+  Ok(VerticalSupport { metrics, substitution })
+}
+
+/// Full names (name ID 4) of the first face of a font file, e.g. "DejaVu Sans Bold".
+pub fn full_names(data: &[u8]) -> Vec<String> {
+  names(data, 4)
+}
+
+/// Family and style of the first face of a font file, e.g. ("DejaVu Sans", "Bold"). Typographic
+/// names win over the legacy ones, which only know four styles per family.
+pub fn family_style(data: &[u8]) -> Option<(String, String)> {
+  let family = names(data, 16).into_iter().chain(names(data, 1)).next()?;
+  let style = names(data, 17).into_iter().chain(names(data, 2)).next()?;
+  Some((family, style))
+}
+
+/// Names with the ID `id` in every Unicode or Windows encoded language the font has, US English first.
+fn names(data: &[u8], id: u16) -> Vec<String> {
+  let face = if data.starts_with(b"ttcf") {
+    read_u32(data, 12).unwrap_or_default() as usize
+  } else {
+    0
+  };
+  let name = table_directory(data, face)
+    .and_then(|tables| tables.into_iter().find(|(tag, _, _)| tag == b"name"))
+    .and_then(|(_, offset, length)| data.get(offset..offset + length));
+  let Some(name) = name else {
+    return vec![];
+  };
+  let count = read_u16(name, 2).unwrap_or_default() as usize;
+  let storage = read_u16(name, 4).unwrap_or_default() as usize;
+  let mut names: Vec<(bool, String)> = vec![];
+  for index in 0..count {
+    let record = 6 + index * 12;
+    let fields = [0, 4, 6, 8, 10].map(|field| read_u16(name, record + field));
+    let [Some(platform), Some(language), Some(name_id), Some(length), Some(offset)] = fields else {
+      break;
+    };
+    // Unicode and Windows names are UTF-16BE, Macintosh ones are left out
+    if name_id != id || ! (platform == 0 || platform == 3) {
+      continue;
+    }
+    let start = storage + offset as usize;
+    let Some(bytes) = name.get(start..start + length as usize) else {
+      continue;
+    };
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+    let text = String::from_utf16_lossy(&units);
+    if ! names.iter().any(|(_, n)| *n == text) {
+      names.push((platform == 3 && language == 0x409, text));
+    }
+  }
+  // Stable, so the order of the table is kept otherwise
+  names.sort_by_key(|(english, _)| ! english);
+  names.into_iter().map(|(_, text)| text).collect()
+}
+
+/// Tag, offset and length of every table of the face starting at `face`.
+fn table_directory(data: &[u8], face: usize) -> Option<Vec<([u8; 4], usize, usize)>> {
+  let count = read_u16(data, face + 4)? as usize;
+  let mut tables = vec![];
+  for index in 0..count {
+    let record = face + 12 + index * 16;
+    let tag: [u8; 4] = data.get(record..record + 4)?.try_into().ok()?;
+    tables.push((tag, read_u32(data, record + 8)? as usize, read_u32(data, record + 12)? as usize));
+  }
+  Some(tables)
+}
+
+/// Feature tags listed in a GSUB table.
+fn feature_tags(gsub: &[u8]) -> Option<Vec<[u8; 4]>> {
+  let list = read_u16(gsub, 6)? as usize;
+  let count = read_u16(gsub, list)? as usize;
+  let mut tags = vec![];
+  for index in 0..count {
+    let record = list + 2 + index * 6;
+    tags.push(gsub.get(record..record + 4)?.try_into().ok()?);
+  }
+  Some(tags)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+  Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+  Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
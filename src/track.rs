@@ -0,0 +1,163 @@
+use std::path::Path;
+use clap::{Arg, ArgAction, ArgMatches};
+
+/// ISO 639-1 codes with the ISO 639-2/B code Matroska uses for them (e.g. "de=ger").
+const LANGUAGES_639_1: &str = "aa=aar ab=abk ae=ave af=afr ak=aka am=amh an=arg ar=ara as=asm av=ava ay=aym az=aze \
+  ba=bak be=bel bg=bul bh=bih bi=bis bm=bam bn=ben bo=tib br=bre bs=bos ca=cat ce=che ch=cha co=cos cr=cre cs=cze \
+  cu=chu cv=chv cy=wel da=dan de=ger dv=div dz=dzo ee=ewe el=gre en=eng eo=epo es=spa et=est eu=baq fa=per ff=ful \
+  fi=fin fj=fij fo=fao fr=fre fy=fry ga=gle gd=gla gl=glg gn=grn gu=guj gv=glv ha=hau he=heb hi=hin ho=hmo hr=hrv \
+  ht=hat hu=hun hy=arm hz=her ia=ina id=ind ie=ile ig=ibo ii=iii ik=ipk io=ido is=ice it=ita iu=iku ja=jpn jv=jav \
+  ka=geo kg=kon ki=kik kj=kua kk=kaz kl=kal km=khm kn=kan ko=kor kr=kau ks=kas ku=kur kv=kom kw=cor ky=kir la=lat \
+  lb=ltz lg=lug li=lim ln=lin lo=lao lt=lit lu=lub lv=lav mg=mlg mh=mah mi=mao mk=mac ml=mal mn=mon mr=mar ms=may \
+  mt=mlt my=bur na=nau nb=nob nd=nde ne=nep ng=ndo nl=dut nn=nno no=nor nr=nbl nv=nav ny=nya oc=oci oj=oji om=orm \
+  or=ori os=oss pa=pan pi=pli pl=pol ps=pus pt=por qu=que rm=roh rn=run ro=rum ru=rus rw=kin sa=san sc=srd sd=snd \
+  se=sme sg=sag si=sin sk=slo sl=slv sm=smo sn=sna so=som sq=alb sr=srp ss=ssw st=sot su=sun sv=swe sw=swa ta=tam \
+  te=tel tg=tgk th=tha ti=tir tk=tuk tl=tgl tn=tsn to=ton tr=tur ts=tso tt=tat tw=twi ty=tah ug=uig uk=ukr ur=urd \
+  uz=uzb ve=ven vi=vie vo=vol wa=wln wo=wol xh=xho yi=yid yo=yor za=zha zh=chi zu=zul";
+
+/// ISO 639-2 codes, both the bibliographic and the terminology ones (e.g. "ger" and "deu").
+const LANGUAGES_639_2: &str = "aar abk ace ach ada ady afa afh afr ain aka akk alb ale alg alt amh ang anp apa ara arc arg arm arn arp art arw asm \
+  ast ath aus ava ave awa aym aze bad bai bak bal bam ban baq bas bat bej bel bem ben ber bho bih bik bin bis bla bnt \
+  bod bos bra bre btk bua bug bul bur byn cad cai car cat cau ceb cel ces cha chb che chg chi chk chm chn cho chp chr \
+  chu chv chy cmc cnr cop cor cos cpe cpf cpp cre crh crp csb cus cym cze dak dan dar day del den deu dgr din div doi \
+  dra dsb dua dum dut dyu dzo efi egy eka ell elx eng enm epo est eus ewe ewo fan fao fas fat fij fil fin fiu fon fra \
+  fre frm fro frr frs fry ful fur gaa gay gba gem geo ger gez gil gla gle glg glv gmh goh gon gor got grb grc gre grn \
+  gsw guj gwi hai hat hau haw heb her hil him hin hit hmn hmo hrv hsb hun hup hye iba ibo ice ido iii ijo iku ile ilo \
+  ina inc ind ine inh ipk ira iro isl ita jav jbo jpn jpr jrb kaa kab kac kal kam kan kar kas kat kau kaw kaz kbd kha \
+  khi khm kho kik kin kir kmb kok kom kon kor kos kpe krc krl kro kru kua kum kur kut lad lah lam lao lat lav lez lim \
+  lin lit lol loz ltz lua lub lug lui lun luo lus mac mad mag mah mai mak mal man mao map mar mas may mdf mdr men mga \
+  mic min mis mkd mkh mlg mlt mnc mni mno moh mon mos mri msa mul mun mus mwl mwr mya myn myv nah nai nap nau nav nbl \
+  nde ndo nds nep new nia nic niu nld nno nob nog non nor nqo nso nub nwc nya nym nyn nyo nzi oci oji ori orm osa oss \
+  ota oto paa pag pal pam pan pap pau peo per phi phn pli pol pon por pra pro pus que raj rap rar roa roh rom ron rum \
+  run rup rus sad sag sah sai sal sam san sas sat scn sco sel sem sga sgn shn sid sin sio sit sla slk slo slv sma sme \
+  smi smj smn smo sms sna snd snk sog som son sot spa sqi srd srn srp srr ssa ssw suk sun sus sux swa swe syc syr tah \
+  tai tam tat tel tem ter tet tgk tgl tha tib tig tir tiv tkl tlh tli tmh tog ton tpi tsi tsn tso tuk tum tup tur tut \
+  tvl twi tyv udm uga uig ukr umb und urd uzb vai ven vie vol vot wak wal war was wel wen wln wol xal xho yao yap yid \
+  yor ypk zap zbl zen zgh zha zho znd zul zun zxx zza";
+
+/// Metadata of a subtitle track in the container.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackOptions {
+  /// BCP-47 or ISO 639-2 tag
+  pub language: Option<String>,
+  pub title: Option<String>,
+  pub default: bool,
+  pub forced: bool
+}
+
+pub fn track_args() -> [Arg; 4] {
+  [
+    Arg::new("language")
+    .long("language")
+    .help("Language of the subtitle track, e.g. \"en\", \"pt-BR\" or \"jpn\". Otherwise taken from names like ep01.en.ass.")
+    .required(false)
+    .action(ArgAction::Set)
+    .value_parser(parse_language),
+    Arg::new("title")
+    .long("title")
+    .help("Name of the subtitle track.")
+    .required(false)
+    .action(ArgAction::Set),
+    Arg::new("default")
+    .long("default")
+    .help("Mark the subtitle track as default, or the first one of a group. Also set by names like ep01.en.default.ass, which win over this.")
+    .required(false)
+    .action(ArgAction::SetTrue),
+    Arg::new("forced")
+    .long("forced")
+    .help("Mark the subtitle track as forced. Also set by names like ep01.en.forced.ass.")
+    .required(false)
+    .action(ArgAction::SetTrue)
+  ]
+}
+
+impl TrackOptions {
+  pub fn from_matches(matches: &ArgMatches) -> TrackOptions {
+    TrackOptions {
+      language: matches.get_one::<String>("language").cloned(),
+      title: matches.get_one::<String>("title").cloned(),
+      default: matches.get_flag("default"),
+      forced: matches.get_flag("forced")
+    }
+  }
+
+  /// The options for one subtitle file. What was given on the command line wins over the file name,
+  /// whose trailing parts like `.en.forced` in ep01.en.forced.ass are read as language and flags.
+  pub fn for_file(&self, path: &Path) -> TrackOptions {
+    let mut track = self.clone();
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let mut language: Option<String> = None;
+    // The first part is the name itself, even if it looks like a language
+    for part in stem.split('.').skip(1).collect::<Vec<_>>().into_iter().rev() {
+      match part.to_lowercase().as_str() {
+        "default" => track.default = true,
+        "forced" => track.forced = true,
+        _ if language.is_none() && is_language(part) => language = Some(part.to_string()),
+        _ => break
+      }
+    }
+    track.language = track.language.or(language);
+    track
+  }
+
+  /// The options for the files of one container, of which only one track can be the default: the
+  /// first one whose name marks it as default, or else the first one if `--default` was given.
+  pub fn for_group(&self, paths: &[&Path]) -> Vec<TrackOptions> {
+    let named = TrackOptions { default: false, ..self.clone() };
+    let default = paths.iter().position(|path| named.for_file(path).default).or(self.default.then_some(0));
+    paths.iter().enumerate()
+      .map(|(index, path)| TrackOptions { default: Some(index) == default, ..self.for_file(path) })
+      .collect()
+  }
+}
+
+/// Checks `--language`, which has to be a tag `is_language` knows.
+fn parse_language(tag: &str) -> Result<String, String> {
+  match is_language(tag) {
+    true => Ok(tag.to_string()),
+    false => Err("expected an ISO 639 code like \"en\" or \"jpn\", optionally with BCP-47 subtags like \"pt-BR\"".to_string())
+  }
+}
+
+/// ISO 639-1 or 639-2 code, optionally followed by BCP-47 subtags like in pt-BR or zh-Hant.
+fn is_language(tag: &str) -> bool {
+  iso_639_2(tag).is_some()
+    && tag.split('-').skip(1).all(|s| (2..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// The ISO 639-2 code of a tag's primary language, which is all Matroska's Language element can
+/// hold: "pt-BR" becomes "por", three letter codes are kept.
+pub fn iso_639_2(tag: &str) -> Option<String> {
+  let primary = tag.split('-').next().unwrap_or_default().to_lowercase();
+  match primary.len() {
+    2 => LANGUAGES_639_1.split_whitespace()
+      .find_map(|pair| pair.strip_prefix(&primary)?.strip_prefix('='))
+      .map(|code| code.to_string()),
+    3 => LANGUAGES_639_2.split_whitespace().any(|code| code == primary).then_some(primary),
+    _ => None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn languages_map_to_iso_639_2() {
+    assert_eq!(iso_639_2("en").as_deref(), Some("eng"));
+    assert_eq!(iso_639_2("de").as_deref(), Some("ger"));
+    assert_eq!(iso_639_2("pt-BR").as_deref(), Some("por"));
+    assert_eq!(iso_639_2("JPN").as_deref(), Some("jpn"));
+    assert_eq!(iso_639_2("xx"), None);
+    assert_eq!(iso_639_2("e"), None);
+  }
+
+  #[test]
+  fn language_tags_need_known_codes_and_valid_subtags() {
+    assert!(is_language("zh-Hant"));
+    assert!(is_language("deu"));
+    assert!(! is_language("xx"));
+    assert!(! is_language("en-"));
+    assert!(! is_language("en-toolongsubtag"));
+  }
+}
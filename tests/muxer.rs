@@ -0,0 +1,152 @@
+//! Runs `fa_tool run` against stub `ffmpeg` and `mkvmerge` executables that only record their
+//! arguments, so the muxer calls can be checked without either program installed. The font comes
+//! from `tests/fixtures` through a mapping file, so nothing has to be installed either.
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Writes its arguments to args.txt and the subtitle file it was given to script.ass.
+const STUB: &str = "#!/bin/sh\nprintf '%s\\n' \"$@\" > \"$(dirname \"$0\")/args.txt\"\n\
+  for arg; do case \"$arg\" in *.ass) cat \"$arg\" > \"$(dirname \"$0\")/script.ass\";; esac; done\n";
+
+/// Facename of the bundled fixture font.
+const FAMILY: &str = "Fixture Sans";
+
+fn fixture_font() -> PathBuf {
+  Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("FixtureSans-Regular.ttf")
+}
+
+/// A fresh folder with the stub muxers, a mapping file for the fixture font and one subtitle file,
+/// ep01.en.forced.ass.
+fn setup(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("fa_tool-{}-{}", name, std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(dir.join("bin")).unwrap();
+  for program in ["ffmpeg", "mkvmerge"] {
+    let path = dir.join("bin").join(program);
+    fs::write(&path, STUB).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+  }
+  let map = format!("[fonts]\n\"{}\" = {:?}\n", FAMILY, fixture_font().to_string_lossy());
+  fs::write(dir.join("fonts.toml"), map).unwrap();
+  fs::write(dir.join("ep01.en.forced.ass"), script("Hello")).unwrap();
+  dir
+}
+
+/// A subtitle file using the fixture font with one event showing `text`.
+fn script(text: &str) -> String {
+  format!("[Script Info]\nScriptType: v4.00+\n\n[V4+ Styles]\n\
+    Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+    Style: Default,{},20,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,2,2,10,10,10,1\n\n[Events]\n\
+    Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+    Dialogue: 0,0:00:00.00,0:00:01.00,Default,,0,0,0,,{}\n", FAMILY, text)
+}
+
+/// Arguments the stub was called with, or None if `run` failed. `extra` is passed on to `run`.
+fn run(dir: &Path, muxer: &str, extra: &[&str]) -> Option<Vec<String>> {
+  let path = format!("{}:{}", dir.join("bin").display(), std::env::var("PATH").unwrap_or_default());
+  let status = Command::new(env!("CARGO_BIN_EXE_fa_tool"))
+    .args(["run", "--muxer", muxer, "--title", "Full", "--map"])
+    .arg(dir.join("fonts.toml"))
+    .args(extra)
+    .arg(dir.join("ep01.en.forced.ass"))
+    .env("PATH", path)
+    .status()
+    .unwrap();
+  if ! status.success() {
+    return None;
+  }
+  let args = fs::read_to_string(dir.join("bin").join("args.txt")).unwrap();
+  Some(args.lines().map(|a| a.to_string()).collect())
+}
+
+/// Whether `args` has `option` directly followed by `value`.
+fn has(args: &[String], option: &str, value: &str) -> bool {
+  args.windows(2).any(|pair| pair[0] == option && pair[1] == value)
+}
+
+#[test]
+fn ffmpeg_gets_tracks_and_attachments() {
+  let dir = setup("ffmpeg");
+  let args = run(&dir, "ffmpeg", &[]).expect("run failed");
+  let output = dir.join("ep01.en.forced.ass.mkv");
+  assert!(has(&args, "-i", &dir.join("ep01.en.forced.ass").to_string_lossy()));
+  assert!(has(&args, "-attach", &fixture_font().to_string_lossy()));
+  assert!(has(&args, "-metadata:s:s:0", "language=eng"));
+  assert!(has(&args, "-metadata:s:s:0", "title=Full"));
+  assert!(has(&args, "-disposition:s:0", "forced"));
+  assert!(has(&args, "matroska", &output.to_string_lossy()));
+  assert_eq!(args.last().map(|a| a.as_str()), Some("-n"));
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn mkvmerge_gets_tracks_and_attachments() {
+  let dir = setup("mkvmerge");
+  let args = run(&dir, "mkvmerge", &[]).expect("run failed");
+  let input = dir.join("ep01.en.forced.ass");
+  assert!(has(&args, "-o", &dir.join("ep01.en.forced.ass.mkv").to_string_lossy()));
+  assert!(has(&args, "--language", "0:en"));
+  assert!(has(&args, "--track-name", "0:Full"));
+  assert!(has(&args, "--default-track-flag", "0:no"));
+  assert!(has(&args, "--forced-display-flag", "0:yes"));
+  // Track options have to come before the file they apply to
+  let position = args.iter().position(|a| *a == *input.to_string_lossy()).unwrap();
+  assert!(args.iter().position(|a| a == "--language").unwrap() < position);
+  assert!(args.iter().any(|a| a == "--attachment-mime-type"));
+  assert!(has(&args, "--attachment-name", "FixtureSans-Regular.ttf"));
+  assert!(has(&args, "--attach-file", &fixture_font().to_string_lossy()));
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn legacy_encodings_are_muxed_as_utf8() {
+  let dir = setup("shift_jis");
+  let input = dir.join("ep01.en.forced.ass");
+  let text = script("こんにちは");
+  fs::write(&input, encoding_rs::SHIFT_JIS.encode(&text).0).unwrap();
+  let args = run(&dir, "ffmpeg", &["--encoding", "shift_jis"]).expect("run failed");
+  // The muxer gets a converted copy, which is removed again afterwards
+  let position = args.iter().position(|a| a == "-i").unwrap();
+  assert_ne!(args[position + 1], input.to_string_lossy());
+  assert!(! Path::new(&args[position + 1]).exists());
+  assert_eq!(fs::read_to_string(dir.join("bin").join("script.ass")).unwrap(), text);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn malformed_mapping_files_fail_check_and_run() {
+  let dir = setup("malformed_map");
+  fs::write(dir.join("fonts.toml"), "[fonts]\n\"Fixture Sans\" = \n").unwrap();
+  assert!(run(&dir, "ffmpeg", &[]).is_none());
+  assert!(! dir.join("bin").join("args.txt").exists());
+  let status = Command::new(env!("CARGO_BIN_EXE_fa_tool"))
+    .args(["check", "--map"])
+    .arg(dir.join("fonts.toml"))
+    .arg(dir.join("ep01.en.forced.ass"))
+    .status()
+    .unwrap();
+  assert!(! status.success());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn dry_runs_show_the_original_subtitle_file() {
+  let dir = setup("dry_run");
+  let input = dir.join("ep01.en.forced.ass");
+  fs::write(&input, encoding_rs::SHIFT_JIS.encode(&script("こんにちは")).0).unwrap();
+  let output = Command::new(env!("CARGO_BIN_EXE_fa_tool"))
+    .args(["run", "--dry-run", "--encoding", "shift_jis", "--map"])
+    .arg(dir.join("fonts.toml"))
+    .arg(&input)
+    .output()
+    .unwrap();
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  let command = stdout.lines().find(|line| line.trim_start().starts_with("ffmpeg ")).unwrap();
+  assert!(command.contains(&format!("-i {}", input.display())));
+  assert!(stdout.contains("converted from Shift_JIS to UTF-8"));
+  fs::remove_dir_all(dir).unwrap();
+}